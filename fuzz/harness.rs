@@ -0,0 +1,75 @@
+// Fuzz target bodies, shared by the cargo-fuzz targets and by
+// tests/fuzz_regressions.rs which replays the saved crash corpus through them.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Once};
+
+use tokio::net::UdpSocket;
+
+use rust_of_rods::config::Config;
+use rust_of_rods::listener::{Listener, Transport};
+use rornet::{
+    Bytes, MessageType, Packet, ServerInfo, StreamRegister, StreamUnregister, UserInfo,
+};
+
+/// Full message decode: header, size checks and typed payload.
+pub fn decode_header(data: &[u8]) {
+    if let Ok((head, packet)) = Packet::decode(&Bytes::copy_from_slice(data)) {
+        // Whatever decodes has to encode to a message that decodes to the
+        // same thing. Not necessarily the same bytes, Hello drops NUL padding.
+        let encoded = packet.encode(head.source, head.streamid).unwrap();
+        let (_, again) = Packet::decode(&encoded).unwrap();
+        assert_eq!(again.encode(head.source, head.streamid).unwrap(), encoded);
+    }
+}
+
+/// Each payload decoder on its own, the first byte picks which one.
+pub fn decode_payload(data: &[u8]) {
+    let Some((&which, payload)) = data.split_first() else { return };
+    match which % 5 {
+        0 => { let _ = UserInfo::decode(payload); },
+        1 => { let _ = ServerInfo::decode(payload); },
+        2 => { let _ = StreamRegister::decode(MessageType::StreamRegister, payload); },
+        3 => { let _ = StreamRegister::decode(MessageType::StreamRegisterResult, payload); },
+        _ => { let _ = StreamUnregister::decode(payload); },
+    }
+}
+
+/// Feeds a sequence of datagrams from a handful of peers through the
+/// listener's client state machine. The input is split into records of
+/// `[peer: u8][len: u16 le][datagram]`.
+pub fn client_state_machine(data: &[u8]) {
+    // The config is global, built once for every input that follows
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        Config::build();
+    });
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        // Replies go to closed ports on localhost and are dropped there
+        let socket = Transport::Udp(Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()));
+        let listener = Listener::new();
+
+        for (peer, datagram) in datagrams(data) {
+            let src_addr = SocketAddr::from(([127, 0, 0, 1], 40000 + (peer % 4) as u16));
+            let datagram = Bytes::copy_from_slice(datagram);
+            listener.process_client_data(&socket, src_addr, datagram).await;
+        }
+    });
+}
+
+fn datagrams(mut data: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut out = Vec::new();
+    while data.len() >= 3 {
+        let peer = data[0];
+        let len = u16::from_le_bytes([data[1], data[2]]) as usize;
+        let end = (3 + len).min(data.len());
+        out.push((peer, &data[3..end]));
+        data = &data[end..];
+    }
+    out
+}
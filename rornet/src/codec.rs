@@ -0,0 +1,356 @@
+use std::fmt;
+use std::str;
+
+use bincode::Options;
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::fixed_str::until_nul;
+use crate::{
+    wire, Header, MessageType, ServerInfo, StreamRegister, StreamUnregister, UserInfo,
+    RORNET_HEADER_SIZE, RORNET_MAX_MESSAGE_LENGTH,
+};
+
+/* Packet codec. Every message on the wire is a Header followed by exactly
+   `header.size` bytes of payload. */
+
+#[derive(Debug)]
+pub enum DecodeError {
+    TooShort(usize),                              // not even a full header
+    TooLarge(u32),                                // size above RORNET_MAX_MESSAGE_LENGTH
+    SizeMismatch { expected: u32, actual: usize },// header size does not match the payload
+    InvalidPayload(MessageType, String),          // payload does not decode for its command
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TooShort(len) =>
+                write!(f, "message of {} bytes is shorter than a header", len),
+            DecodeError::TooLarge(size) =>
+                write!(f, "payload size {} exceeds the maximum of {}", size, RORNET_MAX_MESSAGE_LENGTH),
+            DecodeError::SizeMismatch { expected, actual } =>
+                write!(f, "header announces {} payload bytes but {} were received", expected, actual),
+            DecodeError::InvalidPayload(command, reason) =>
+                write!(f, "invalid {:?} payload: {}", command, reason),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[derive(Debug)]
+pub enum EncodeError {
+    TooLarge(usize),                              // payload above RORNET_MAX_MESSAGE_LENGTH
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::TooLarge(size) =>
+                write!(f, "payload size {} exceeds the maximum of {}", size, RORNET_MAX_MESSAGE_LENGTH),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+pub enum Packet {
+    Hello(String),                    // client greeting with its protocol version
+    ServerInfo(Box<ServerInfo>),      // server answer to a Hello, sent as a Hello
+    UserInfo(MessageType, Box<UserInfo>), // user info, sent as UserInfo, Welcome or UserJoin
+    StreamRegister(MessageType, Box<StreamRegister>), // sent as StreamRegister or StreamRegisterResult
+    StreamUnregister(StreamUnregister),
+    Raw(MessageType, Bytes),          // anything without a typed payload, e.g. stream data
+}
+
+impl Packet {
+    pub fn command(&self) -> MessageType {
+        match self {
+            Packet::Hello(_) | Packet::ServerInfo(_) => MessageType::Hello,
+            Packet::UserInfo(command, _) => *command,
+            Packet::StreamRegister(command, _) => *command,
+            Packet::StreamUnregister(_) => MessageType::StreamUnregister,
+            Packet::Raw(command, _) => *command,
+        }
+    }
+
+    /// Payload size announced by a message header, for stream transports
+    /// that need to know how much more to read. Rejects sizes above the
+    /// protocol maximum before anything gets allocated for them.
+    pub fn frame_size(head_raw: &[u8]) -> Result<usize, DecodeError> {
+        if head_raw.len() < RORNET_HEADER_SIZE {
+            return Err(DecodeError::TooShort(head_raw.len()));
+        }
+        let head: Header = wire().deserialize(&head_raw[..RORNET_HEADER_SIZE])
+            .map_err(|_| DecodeError::TooShort(head_raw.len()))?;
+        if head.size > RORNET_MAX_MESSAGE_LENGTH {
+            return Err(DecodeError::TooLarge(head.size));
+        }
+        Ok(head.size as usize)
+    }
+
+    /// Decodes one message sent by a client. Sizes are checked against the
+    /// header before any payload is looked at. Payloads without a typed
+    /// struct are not copied, they share the memory of `data`.
+    pub fn decode(data: &Bytes) -> Result<(Header, Packet), DecodeError> {
+        let (head, payload) = Packet::split_frame(data)?;
+        let packet: Packet = match head.command {
+            MessageType::Hello => {
+                // Clients send the bare version string, NUL padding is tolerated
+                let version: &str = str::from_utf8(until_nul(payload))
+                    .map_err(|err| DecodeError::InvalidPayload(head.command, err.to_string()))?;
+                if version.is_empty() {
+                    return Err(DecodeError::InvalidPayload(head.command, "missing version".into()));
+                }
+                Packet::Hello(version.to_string())
+            },
+            MessageType::UserInfo => {
+                Packet::UserInfo(head.command, Box::new(UserInfo::decode(payload)?))
+            },
+            MessageType::StreamRegister | MessageType::StreamRegisterResult => {
+                Packet::StreamRegister(head.command, Box::new(StreamRegister::decode(head.command, payload)?))
+            },
+            MessageType::StreamUnregister => {
+                Packet::StreamUnregister(StreamUnregister::decode(payload)?)
+            },
+            command => Packet::Raw(command, data.slice(RORNET_HEADER_SIZE..)),
+        };
+        Ok((head, packet))
+    }
+
+    /// Decodes one message sent by a server, for anything that talks to one
+    /// as a client. Some commands carry another payload in this direction: a
+    /// Hello is the server's ServerInfo, Welcome and UserJoin carry the
+    /// UserInfo of the user they are about.
+    pub fn decode_from_server(data: &Bytes) -> Result<(Header, Packet), DecodeError> {
+        let (head, payload) = Packet::split_frame(data)?;
+        let packet: Packet = match head.command {
+            MessageType::Hello => {
+                Packet::ServerInfo(Box::new(ServerInfo::decode(payload)?))
+            },
+            MessageType::UserInfo | MessageType::Welcome | MessageType::UserJoin => {
+                Packet::UserInfo(head.command, Box::new(UserInfo::decode_as(head.command, payload)?))
+            },
+            MessageType::StreamRegister | MessageType::StreamRegisterResult => {
+                Packet::StreamRegister(head.command, Box::new(StreamRegister::decode(head.command, payload)?))
+            },
+            MessageType::StreamUnregister => {
+                Packet::StreamUnregister(StreamUnregister::decode(payload)?)
+            },
+            command => Packet::Raw(command, data.slice(RORNET_HEADER_SIZE..)),
+        };
+        Ok((head, packet))
+    }
+
+    // Splits a message into its header and payload, once the header is known
+    // to describe the payload that came with it
+    fn split_frame(data: &Bytes) -> Result<(Header, &[u8]), DecodeError> {
+        if data.len() < RORNET_HEADER_SIZE {
+            return Err(DecodeError::TooShort(data.len()));
+        }
+        let (head_raw, payload) = data.split_at(RORNET_HEADER_SIZE);
+        let head: Header = wire().deserialize(head_raw)
+            .map_err(|_| DecodeError::TooShort(data.len()))?;
+
+        if head.size > RORNET_MAX_MESSAGE_LENGTH {
+            return Err(DecodeError::TooLarge(head.size));
+        }
+        if head.size as usize != payload.len() {
+            return Err(DecodeError::SizeMismatch { expected: head.size, actual: payload.len() });
+        }
+        Ok((head, payload))
+    }
+
+    /// Encodes the message with a header for the given source and stream.
+    /// The result is reference counted, so one encoded message can be handed
+    /// to any number of peers without copying it again.
+    pub fn encode(&self, source: i32, streamid: u32) -> Result<Bytes, EncodeError> {
+        let typed: Vec<u8>;
+        let payload: &[u8] = match self {
+            Packet::Hello(version) => version.as_bytes(),
+            Packet::ServerInfo(info) => { typed = info.encode(); &typed },
+            Packet::UserInfo(_, user) => { typed = user.encode(); &typed },
+            Packet::StreamRegister(_, reg) => { typed = reg.encode(); &typed },
+            Packet::StreamUnregister(unreg) => { typed = wire().serialize(unreg).unwrap(); &typed },
+            Packet::Raw(_, payload) => payload,
+        };
+        if payload.len() > RORNET_MAX_MESSAGE_LENGTH as usize {
+            return Err(EncodeError::TooLarge(payload.len()));
+        }
+
+        let mut msg: BytesMut = BytesMut::with_capacity(RORNET_HEADER_SIZE + payload.len());
+        wire().serialize_into((&mut msg).writer(), &Header {
+            command: self.command(),
+            source,
+            streamid,
+            size: payload.len() as u32,
+        }).unwrap();
+        msg.extend_from_slice(payload);
+        Ok(msg.freeze())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ProtocolVersion, RORNET_VERSION, STREAM_REG_SUCCESS};
+
+    fn server_info() -> ServerInfo {
+        ServerInfo::new(ProtocolVersion::V2_44, "simple2", "test server", false, "hi")
+    }
+
+    fn user(name: &str) -> UserInfo {
+        let mut user: UserInfo = UserInfo::default();
+        user.set_uniqueid(7);
+        user.set_username(name);
+        user
+    }
+
+    #[test]
+    fn server_info_decodes_from_server() {
+        let data: Bytes = Packet::ServerInfo(Box::new(server_info())).encode(0, 0).unwrap();
+        let (head, packet) = Packet::decode_from_server(&data).unwrap();
+        assert_eq!(head.command, MessageType::Hello);
+        match packet {
+            Packet::ServerInfo(info) => {
+                assert_eq!(info.protocol_version.to_str().unwrap(), RORNET_VERSION);
+                assert_eq!(info.server_name.to_str().unwrap(), "test server");
+            },
+            _ => panic!("ServerInfo did not decode as ServerInfo"),
+        }
+    }
+
+    #[test]
+    fn hello_decodes_by_direction() {
+        let hello: Bytes = Packet::Hello(RORNET_VERSION.to_string()).encode(0, 0).unwrap();
+        assert!(matches!(Packet::decode(&hello).unwrap().1, Packet::Hello(version) if version == RORNET_VERSION));
+        // A bare version string is no ServerInfo
+        assert!(matches!(Packet::decode_from_server(&hello),
+            Err(DecodeError::InvalidPayload(MessageType::Hello, _))));
+    }
+
+    #[test]
+    fn welcome_and_user_join_carry_user_info_from_server() {
+        for command in [MessageType::Welcome, MessageType::UserJoin, MessageType::UserInfo] {
+            let data: Bytes = Packet::UserInfo(command, Box::new(user("alice"))).encode(7, 0).unwrap();
+            match Packet::decode_from_server(&data).unwrap() {
+                (head, Packet::UserInfo(decoded, user)) => {
+                    assert_eq!((head.command, decoded), (command, command));
+                    assert_eq!((user.uniqueid(), user.username().as_ref()), (7, "alice"));
+                },
+                _ => panic!("{:?} did not decode as UserInfo", command),
+            }
+        }
+        // Clients never send these, so they stay raw in that direction
+        let welcome: Bytes = Packet::UserInfo(MessageType::Welcome, Box::new(user("alice"))).encode(7, 0).unwrap();
+        assert!(matches!(Packet::decode(&welcome).unwrap().1, Packet::Raw(MessageType::Welcome, _)));
+    }
+
+    #[test]
+    fn truncated_user_info_from_server_names_its_command() {
+        let data: Bytes = Packet::Raw(MessageType::UserJoin, Bytes::from(vec![0; UserInfo::WIRE_SIZE - 1]))
+            .encode(7, 0).unwrap();
+        assert!(matches!(Packet::decode_from_server(&data),
+            Err(DecodeError::InvalidPayload(MessageType::UserJoin, _))));
+    }
+
+    #[test]
+    fn stream_register_result_decodes_from_server() {
+        let mut reg: StreamRegister = StreamRegister::new(crate::StreamType::Character, 7, 10, "default");
+        reg.set_status(STREAM_REG_SUCCESS);
+        let data: Bytes = Packet::StreamRegister(MessageType::StreamRegisterResult, Box::new(reg))
+            .encode(7, 10).unwrap();
+        match Packet::decode_from_server(&data).unwrap().1 {
+            Packet::StreamRegister(MessageType::StreamRegisterResult, reg) => {
+                assert_eq!(reg.status(), STREAM_REG_SUCCESS);
+                assert_eq!((reg.origin_sourceid(), reg.origin_streamid()), (7, 10));
+            },
+            _ => panic!("StreamRegisterResult did not decode as StreamRegister"),
+        }
+    }
+
+    // A raw header, with whatever size it claims
+    fn header(command: MessageType, size: u32) -> Vec<u8> {
+        wire().serialize(&Header { command, source: 0, streamid: 0, size }).unwrap()
+    }
+
+    #[test]
+    fn shorter_than_a_header_is_too_short() {
+        for len in [0, 1, RORNET_HEADER_SIZE - 1] {
+            let data: Bytes = Bytes::from(header(MessageType::Utf8Chat, 0)[..len].to_vec());
+            assert!(matches!(Packet::decode(&data), Err(DecodeError::TooShort(short)) if short == len));
+            assert!(matches!(Packet::frame_size(&data), Err(DecodeError::TooShort(short)) if short == len));
+        }
+    }
+
+    #[test]
+    fn size_above_the_maximum_is_too_large() {
+        let size: u32 = RORNET_MAX_MESSAGE_LENGTH + 1;
+        let head: Vec<u8> = header(MessageType::StreamData, size);
+        // Rejected on the header alone, before any payload arrives
+        assert!(matches!(Packet::frame_size(&head), Err(DecodeError::TooLarge(large)) if large == size));
+
+        let mut data: Vec<u8> = head;
+        data.resize(RORNET_HEADER_SIZE + size as usize, 0);
+        assert!(matches!(Packet::decode(&Bytes::from(data)), Err(DecodeError::TooLarge(large)) if large == size));
+
+        let head: Vec<u8> = header(MessageType::StreamData, u32::MAX);
+        assert!(matches!(Packet::decode(&Bytes::from(head)), Err(DecodeError::TooLarge(u32::MAX))));
+    }
+
+    #[test]
+    fn maximum_size_is_accepted() {
+        let mut data: Vec<u8> = header(MessageType::StreamData, RORNET_MAX_MESSAGE_LENGTH);
+        data.resize(RORNET_HEADER_SIZE + RORNET_MAX_MESSAGE_LENGTH as usize, 0);
+        let (head, packet) = Packet::decode(&Bytes::from(data)).unwrap();
+        assert_eq!(head.size, RORNET_MAX_MESSAGE_LENGTH);
+        assert!(matches!(packet, Packet::Raw(MessageType::StreamData, payload)
+            if payload.len() == RORNET_MAX_MESSAGE_LENGTH as usize));
+    }
+
+    #[test]
+    fn header_claiming_more_or_less_than_it_has_is_a_mismatch() {
+        let mut data: Vec<u8> = header(MessageType::Utf8Chat, 10);
+        data.extend_from_slice(b"short");
+        assert!(matches!(Packet::decode(&Bytes::from(data.clone())),
+            Err(DecodeError::SizeMismatch { expected: 10, actual: 5 })));
+        assert!(matches!(Packet::decode_from_server(&Bytes::from(data)),
+            Err(DecodeError::SizeMismatch { expected: 10, actual: 5 })));
+
+        let mut data: Vec<u8> = header(MessageType::Utf8Chat, 2);
+        data.extend_from_slice(b"too long");
+        assert!(matches!(Packet::decode(&Bytes::from(data)),
+            Err(DecodeError::SizeMismatch { expected: 2, actual: 8 })));
+    }
+
+    #[test]
+    fn payload_that_does_not_fit_its_command_is_invalid() {
+        let mut data: Vec<u8> = header(MessageType::UserInfo, 4);
+        data.extend_from_slice(&[0; 4]);
+        assert!(matches!(Packet::decode(&Bytes::from(data)),
+            Err(DecodeError::InvalidPayload(MessageType::UserInfo, _))));
+
+        let data: Bytes = Bytes::from(header(MessageType::Hello, 0));
+        assert!(matches!(Packet::decode(&data), Err(DecodeError::InvalidPayload(MessageType::Hello, _))));
+    }
+
+    #[test]
+    fn encode_rejects_payloads_above_the_maximum() {
+        let max: usize = RORNET_MAX_MESSAGE_LENGTH as usize;
+        let fits: Packet = Packet::Raw(MessageType::StreamData, Bytes::from(vec![0; max]));
+        assert_eq!(fits.encode(1, 2).unwrap().len(), RORNET_HEADER_SIZE + max);
+
+        let too_large: Packet = Packet::Raw(MessageType::StreamData, Bytes::from(vec![0; max + 1]));
+        assert!(matches!(too_large.encode(1, 2), Err(EncodeError::TooLarge(size)) if size == max + 1));
+        let hello: Packet = Packet::Hello("x".repeat(max + 1));
+        assert!(matches!(hello.encode(0, 0), Err(EncodeError::TooLarge(_))));
+    }
+
+    #[test]
+    fn encode_then_decode_round_trips() {
+        let packet: Packet = Packet::Raw(MessageType::Utf8Chat, Bytes::from_static(b"hello"));
+        let (head, decoded) = Packet::decode(&packet.encode(3, 4).unwrap()).unwrap();
+        assert_eq!((head.command, head.source, head.streamid, head.size), (MessageType::Utf8Chat, 3, 4, 5));
+        assert!(matches!(decoded, Packet::Raw(MessageType::Utf8Chat, payload) if &payload[..] == b"hello"));
+    }
+}
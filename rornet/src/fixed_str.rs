@@ -0,0 +1,151 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::str::{self, Utf8Error};
+
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde_big_array::BigArray;
+
+/// Fixed-width, NUL padded string field of a RoRnet struct (`char name[N]` on
+/// the C++ side). Holds at most N - 1 bytes of text so there is always a
+/// terminating NUL, and never cuts a UTF-8 character in half.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct FixedStr<const N: usize>([u8; N]);
+
+impl<const N: usize> FixedStr<N> {
+    /// Copies `src` in, truncating it to the last character that fits.
+    pub fn new(src: &str) -> FixedStr<N> {
+        let mut end: usize = src.len().min(N.saturating_sub(1));
+        while !src.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut buf: [u8; N] = [0; N];
+        buf[..end].copy_from_slice(&src.as_bytes()[..end]);
+        FixedStr(buf)
+    }
+
+    /// Bytes up to the first NUL, or the whole field if it is not terminated.
+    pub fn as_bytes(&self) -> &[u8] {
+        until_nul(&self.0)
+    }
+
+    /// Strict decode, for fields where garbage should be rejected.
+    pub fn to_str(&self) -> Result<&str, Utf8Error> {
+        str::from_utf8(self.as_bytes())
+    }
+
+    /// Lenient decode, invalid sequences become U+FFFD.
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.as_bytes())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.as_bytes().is_empty()
+    }
+}
+
+impl<const N: usize> Default for FixedStr<N> {
+    fn default() -> FixedStr<N> {
+        FixedStr([0; N])
+    }
+}
+
+impl<const N: usize> fmt::Debug for FixedStr<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.to_string_lossy(), f)
+    }
+}
+
+impl<const N: usize> Serialize for FixedStr<N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        BigArray::serialize(&self.0, serializer)
+    }
+}
+
+impl<'de, const N: usize> Deserialize<'de> for FixedStr<N> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<FixedStr<N>, D::Error> {
+        <[u8; N] as BigArray<'de, u8>>::deserialize(deserializer).map(FixedStr)
+    }
+}
+
+// Cuts a C string buffer at its terminating NUL
+pub(crate) fn until_nul(buf: &[u8]) -> &[u8] {
+    match buf.iter().position(|&b| b == 0) {
+        Some(end) => &buf[..end],
+        None => buf,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_text_is_nul_padded() {
+        let s: FixedStr<8> = FixedStr::new("abc");
+        assert_eq!(s.0, *b"abc\0\0\0\0\0");
+        assert_eq!(s.to_str(), Ok("abc"));
+    }
+
+    #[test]
+    fn truncation_keeps_a_terminating_nul() {
+        let s: FixedStr<4> = FixedStr::new("abcdef");
+        assert_eq!(s.0, *b"abc\0");
+        assert_eq!(s.to_str(), Ok("abc"));
+    }
+
+    #[test]
+    fn truncation_does_not_split_a_character() {
+        // "é" is two bytes and would straddle byte N - 1 = 3
+        let s: FixedStr<4> = FixedStr::new("abé");
+        assert_eq!(s.0, *b"ab\0\0");
+        assert_eq!(s.to_str(), Ok("ab"));
+
+        // A four byte character that does not fit at all
+        let s: FixedStr<4> = FixedStr::new("🚗");
+        assert!(s.is_empty());
+
+        // One that ends exactly at N - 1 is kept
+        let s: FixedStr<4> = FixedStr::new("aé");
+        assert_eq!(s.to_str(), Ok("aé"));
+    }
+
+    #[test]
+    fn zero_sized_field_stays_empty() {
+        let s: FixedStr<0> = FixedStr::new("abc");
+        assert!(s.is_empty());
+    }
+
+    #[test]
+    fn unterminated_field_decodes_whole() {
+        // The C++ side may fill a field completely, e.g. a 40 character
+        // SHA1 in a 40 byte field
+        let s: FixedStr<4> = FixedStr(*b"abcd");
+        assert_eq!(s.as_bytes(), b"abcd");
+        assert_eq!(s.to_str(), Ok("abcd"));
+    }
+
+    #[test]
+    fn decoding_stops_at_the_first_nul() {
+        let s: FixedStr<8> = FixedStr(*b"ab\0cd\0\0\0");
+        assert_eq!(s.as_bytes(), b"ab");
+        assert_eq!(s.to_string_lossy(), "ab");
+    }
+
+    #[test]
+    fn invalid_utf8_fails_strict_decode() {
+        let s: FixedStr<4> = FixedStr(*b"a\xffb\0");
+        assert!(s.to_str().is_err());
+        assert_eq!(s.to_string_lossy(), "a\u{FFFD}b");
+
+        // A character cut off by the end of the field
+        let s: FixedStr<3> = FixedStr(*b"a\xc3\0");
+        assert!(s.to_str().is_err());
+        assert_eq!(s.to_string_lossy(), "a\u{FFFD}");
+    }
+
+    #[test]
+    fn lossy_decode_borrows_valid_text() {
+        let s: FixedStr<8> = FixedStr::new("abc");
+        assert!(matches!(s.to_string_lossy(), Cow::Borrowed("abc")));
+    }
+}
@@ -0,0 +1,56 @@
+//! RoRnet, the Rigs of Rods multiplayer protocol: message types, the
+//! structs sent over the wire, their codec and protocol constants.
+//! Shared by the server and anything else that needs to talk to it.
+
+mod codec;
+mod fixed_str;
+mod message;
+mod structs;
+mod version;
+
+use bincode::Options;
+
+// Re-exported so users of the codec do not need a matching bytes version
+pub use bytes::{Bytes, BytesMut};
+
+pub use codec::{DecodeError, EncodeError, Packet};
+pub use fixed_str::FixedStr;
+pub use message::MessageType;
+pub use structs::{
+    ActorStreamData, Header, ServerInfo, StreamRegister, StreamType, StreamUnregister, UserInfo,
+};
+pub use version::{ProtocolVersion, SUPPORTED_VERSIONS};
+
+pub const RORNET_MAX_PEERS: u32 = 64;
+pub const RORNET_MAX_MESSAGE_LENGTH: u32 = 8192;
+pub const RORNET_LAN_BROADCAST_PORT: u32 = 13000;
+pub const RORNET_MAX_USERNAME_LEN: u32 = 40;
+pub const RORNET_HEADER_SIZE: usize = 16;
+
+// UserInfo authstatus bits
+pub const AUTH_NONE: i32 = 0;
+pub const AUTH_ADMIN: i32 = 1 << 0;
+pub const AUTH_RANKED: i32 = 1 << 1;
+pub const AUTH_MOD: i32 = 1 << 2;
+pub const AUTH_BOT: i32 = 1 << 3;
+pub const AUTH_BANNED: i32 = 1 << 4;
+
+// Newest protocol version, the one announced to serverlists
+pub const RORNET_VERSION: &str = "RoRnet_2.44";
+
+// StreamRegister status values, the result carries success or error
+pub const STREAM_REG_PENDING: i32 = 0;
+pub const STREAM_REG_SUCCESS: i32 = 1;
+pub const STREAM_REG_ERROR: i32 = -1;
+
+// Serialization options matching the C++ structs that the real server and
+// clients memcpy onto the wire: little endian, fixed width integers, arrays
+// without length prefixes and no trailing bytes. Set explicitly so that a
+// bincode upgrade changing its defaults cannot break compatibility.
+pub(crate) fn wire() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_little_endian()
+        .with_fixint_encoding()
+        .with_no_limit()
+        .reject_trailing_bytes()
+}
@@ -0,0 +1,114 @@
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MessageType {
+    Hello,                        // client sends its version as first message
+
+    // Hello responses
+    Full,                         // no more slots for us
+    WrongPw,                      // server send that on wrong pw
+    WrongVer,                     // wrong version
+    Banned,                       // client not allowed to join
+    Welcome,                      // we can proceed
+
+    // Technical
+    Version,                      // server responds with its version
+    ServerSettings,               // server send client the terrain name: server_info_t
+    UserInfo,                     // user data that is sent from the server to the clients
+    MasterInfo,                   // master information response
+    NetQuality,                   // network quality information
+
+    // Gameplay
+    GameCmd,                      // script message, can be sent in both directions
+    UserJoin,                     // new user joined
+    UserLeave,                    // user leaves
+    Utf8Chat,                     // chat line in UTF8 encoding
+    Utf8PrivChat,                 // private chat line in UTF8 encoding
+
+    // Stream functions
+    StreamRegister,               // create new stream
+    StreamRegisterResult,         // result of a stream creation
+    StreamUnregister,             // remove stream
+    StreamData,                   // stream data
+    StreamDataDiscardable,        // stream data that is allowed to be discarded
+
+    // Legacy values (RoRnet_2.38 and earlier)
+    WrongVerLegacy,               // wrong version
+
+    // Anything this server does not know about, kept so that the header
+    // still decodes and the message can be ignored
+    Unknown(u32),
+}
+
+impl From<u32> for MessageType {
+    fn from(command: u32) -> MessageType {
+        match command {
+            1025 => MessageType::Hello,
+            1026 => MessageType::Full,
+            1027 => MessageType::WrongPw,
+            1028 => MessageType::WrongVer,
+            1029 => MessageType::Banned,
+            1030 => MessageType::Welcome,
+            1031 => MessageType::Version,
+            1032 => MessageType::ServerSettings,
+            1033 => MessageType::UserInfo,
+            1034 => MessageType::MasterInfo,
+            1035 => MessageType::NetQuality,
+            1036 => MessageType::GameCmd,
+            1037 => MessageType::UserJoin,
+            1038 => MessageType::UserLeave,
+            1039 => MessageType::Utf8Chat,
+            1040 => MessageType::Utf8PrivChat,
+            1041 => MessageType::StreamRegister,
+            1042 => MessageType::StreamRegisterResult,
+            1043 => MessageType::StreamUnregister,
+            1044 => MessageType::StreamData,
+            1045 => MessageType::StreamDataDiscardable,
+            1003 => MessageType::WrongVerLegacy,
+            other => MessageType::Unknown(other),
+        }
+    }
+}
+
+impl From<MessageType> for u32 {
+    fn from(command: MessageType) -> u32 {
+        match command {
+            MessageType::Hello => 1025,
+            MessageType::Full => 1026,
+            MessageType::WrongPw => 1027,
+            MessageType::WrongVer => 1028,
+            MessageType::Banned => 1029,
+            MessageType::Welcome => 1030,
+            MessageType::Version => 1031,
+            MessageType::ServerSettings => 1032,
+            MessageType::UserInfo => 1033,
+            MessageType::MasterInfo => 1034,
+            MessageType::NetQuality => 1035,
+            MessageType::GameCmd => 1036,
+            MessageType::UserJoin => 1037,
+            MessageType::UserLeave => 1038,
+            MessageType::Utf8Chat => 1039,
+            MessageType::Utf8PrivChat => 1040,
+            MessageType::StreamRegister => 1041,
+            MessageType::StreamRegisterResult => 1042,
+            MessageType::StreamUnregister => 1043,
+            MessageType::StreamData => 1044,
+            MessageType::StreamDataDiscardable => 1045,
+            MessageType::WrongVerLegacy => 1003,
+            MessageType::Unknown(other) => other,
+        }
+    }
+}
+
+// Commands go over the wire as a plain u32
+impl Serialize for MessageType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(u32::from(*self))
+    }
+}
+
+impl<'de> Deserialize<'de> for MessageType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<MessageType, D::Error> {
+        u32::deserialize(deserializer).map(MessageType::from)
+    }
+}
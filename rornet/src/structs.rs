@@ -0,0 +1,372 @@
+use std::borrow::Cow;
+
+use bincode::Options;
+use serde::{Serialize, Deserialize};
+use serde_big_array::BigArray;
+
+use crate::{
+    wire, DecodeError, FixedStr, MessageType, ProtocolVersion,
+    AUTH_ADMIN, AUTH_BOT, AUTH_MOD, AUTH_RANKED, RORNET_MAX_MESSAGE_LENGTH,
+    RORNET_MAX_USERNAME_LEN, STREAM_REG_PENDING,
+};
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Header {
+    pub command: MessageType,
+    pub source: i32,
+    pub streamid: u32,
+    pub size: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub protocol_version: FixedStr<20>,
+    pub terrain: FixedStr<128>,
+    pub server_name: FixedStr<128>,
+    pub has_password: u8,
+    pub info: FixedStr<4096>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct UserInfo {
+    uniqueid: u32,
+    authstatus: i32,
+    slotnum: i32,
+    colournum: i32,
+
+    username: FixedStr<{ RORNET_MAX_USERNAME_LEN as usize }>,
+    usertoken: FixedStr<40>,
+    serverpassword: FixedStr<40>,
+    language: FixedStr<10>,
+    clientname: FixedStr<10>,
+    clientversion: FixedStr<25>,
+    clientguid: FixedStr<40>,
+    sessiontype: FixedStr<10>,
+    sessionoptions: FixedStr<128>,
+}
+
+impl UserInfo {
+    /// Size of the struct as the C++ side sends it. RoRnet.h is wrapped in
+    /// `#pragma pack(push, 1)`, so there is no trailing padding.
+    pub const WIRE_SIZE: usize = 359;
+
+    pub fn decode(payload: &[u8]) -> Result<UserInfo, DecodeError> {
+        UserInfo::decode_as(MessageType::UserInfo, payload)
+    }
+
+    /// Same as `decode`, for the other commands that carry a UserInfo,
+    /// which errors are reported under
+    pub fn decode_as(command: MessageType, payload: &[u8]) -> Result<UserInfo, DecodeError> {
+        if payload.len() != UserInfo::WIRE_SIZE {
+            return Err(DecodeError::InvalidPayload(command,
+                format!("expected {} bytes, got {}", UserInfo::WIRE_SIZE, payload.len())));
+        }
+        wire().deserialize(payload)
+            .map_err(|err| DecodeError::InvalidPayload(command, err.to_string()))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        wire().serialize(self).unwrap()
+    }
+
+    pub fn uniqueid(&self) -> u32 { self.uniqueid }
+    pub fn authstatus(&self) -> i32 { self.authstatus }
+    pub fn slotnum(&self) -> i32 { self.slotnum }
+    pub fn colournum(&self) -> i32 { self.colournum }
+    pub fn username(&self) -> Cow<'_, str> { self.username.to_string_lossy() }
+    pub fn usertoken(&self) -> Cow<'_, str> { self.usertoken.to_string_lossy() }
+    pub fn serverpassword(&self) -> &[u8] { self.serverpassword.as_bytes() }
+    pub fn language(&self) -> Cow<'_, str> { self.language.to_string_lossy() }
+    pub fn client_name(&self) -> Cow<'_, str> { self.clientname.to_string_lossy() }
+    pub fn client_version(&self) -> Cow<'_, str> { self.clientversion.to_string_lossy() }
+    pub fn client_guid(&self) -> Cow<'_, str> { self.clientguid.to_string_lossy() }
+    pub fn session_type(&self) -> Cow<'_, str> { self.sessiontype.to_string_lossy() }
+    pub fn session_options(&self) -> Cow<'_, str> { self.sessionoptions.to_string_lossy() }
+
+    pub fn is_admin(&self) -> bool { self.authstatus & AUTH_ADMIN != 0 }
+    pub fn is_ranked(&self) -> bool { self.authstatus & AUTH_RANKED != 0 }
+    pub fn is_mod(&self) -> bool { self.authstatus & AUTH_MOD != 0 }
+    pub fn is_bot(&self) -> bool { self.authstatus & AUTH_BOT != 0 }
+
+    // Fields the server fills in before passing the info on to other clients
+    pub fn set_uniqueid(&mut self, uniqueid: u32) { self.uniqueid = uniqueid; }
+    pub fn set_authstatus(&mut self, authstatus: i32) { self.authstatus = authstatus; }
+    pub fn set_slotnum(&mut self, slotnum: i32) { self.slotnum = slotnum; }
+    pub fn set_colournum(&mut self, colournum: i32) { self.colournum = colournum; }
+    pub fn set_username(&mut self, username: &str) { self.username = FixedStr::new(username); }
+    pub fn clear_serverpassword(&mut self) { self.serverpassword = FixedStr::default(); }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StreamType {
+    Actor,                        // vehicle, carries ActorStreamData
+    Character,
+    AiTraffic,
+    Chat,
+    Unknown(i32),
+}
+
+impl From<i32> for StreamType {
+    fn from(stream_type: i32) -> StreamType {
+        match stream_type {
+            0 => StreamType::Actor,
+            1 => StreamType::Character,
+            2 => StreamType::AiTraffic,
+            3 => StreamType::Chat,
+            other => StreamType::Unknown(other),
+        }
+    }
+}
+
+impl From<StreamType> for i32 {
+    fn from(stream_type: StreamType) -> i32 {
+        match stream_type {
+            StreamType::Actor => 0,
+            StreamType::Character => 1,
+            StreamType::AiTraffic => 2,
+            StreamType::Chat => 3,
+            StreamType::Unknown(other) => other,
+        }
+    }
+}
+
+/// Announces a new stream. Sent by the owning client as StreamRegister and
+/// answered with the same struct as StreamRegisterResult.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StreamRegister {
+    stream_type: i32,
+    status: i32,
+    origin_sourceid: i32,
+    origin_streamid: i32,
+    name: FixedStr<128>,
+    #[serde(with = "BigArray")]
+    data: [u8; 128],              // stream type specific setup, see ActorStreamData
+}
+
+/// Setup data of an actor stream, stored in `StreamRegister::data`
+/// (`ActorStreamRegister` on the C++ side)
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ActorStreamData {
+    pub buffer_size: i32,
+    pub time: i32,
+    pub skin: FixedStr<60>,
+    pub sectionconfig: FixedStr<60>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct StreamUnregister {
+    pub streamid: u32,
+}
+
+impl StreamRegister {
+    pub const WIRE_SIZE: usize = 272;
+
+    pub fn new(stream_type: StreamType, origin_sourceid: i32, origin_streamid: i32, name: &str) -> StreamRegister {
+        StreamRegister {
+            stream_type: stream_type.into(),
+            status: STREAM_REG_PENDING,
+            origin_sourceid,
+            origin_streamid,
+            name: FixedStr::new(name),
+            data: [0; 128],
+        }
+    }
+
+    /// Decodes and validates a registration, `command` is either
+    /// StreamRegister or StreamRegisterResult.
+    pub fn decode(command: MessageType, payload: &[u8]) -> Result<StreamRegister, DecodeError> {
+        let invalid = |reason: String| DecodeError::InvalidPayload(command, reason);
+
+        if payload.len() != StreamRegister::WIRE_SIZE {
+            return Err(invalid(format!("expected {} bytes, got {}", StreamRegister::WIRE_SIZE, payload.len())));
+        }
+        let reg: StreamRegister = wire().deserialize(payload)
+            .map_err(|err| invalid(err.to_string()))?;
+
+        if let StreamType::Unknown(other) = reg.stream_type() {
+            return Err(invalid(format!("unknown stream type {}", other)));
+        }
+        // The name is a file name the other clients go looking for
+        let name: &str = reg.name.to_str().map_err(|err| invalid(err.to_string()))?;
+        if name.contains(['/', '\\']) || name.contains("..") {
+            return Err(invalid(format!("bad stream name {:?}", name)));
+        }
+        if let Some(actor) = reg.actor_data() {
+            if actor.buffer_size <= 0 || actor.buffer_size as u32 > RORNET_MAX_MESSAGE_LENGTH {
+                return Err(invalid(format!("bad actor buffer size {}", actor.buffer_size)));
+            }
+        }
+        Ok(reg)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        wire().serialize(self).unwrap()
+    }
+
+    pub fn stream_type(&self) -> StreamType { StreamType::from(self.stream_type) }
+    pub fn status(&self) -> i32 { self.status }
+    pub fn origin_sourceid(&self) -> i32 { self.origin_sourceid }
+    pub fn origin_streamid(&self) -> i32 { self.origin_streamid }
+    pub fn name(&self) -> Cow<'_, str> { self.name.to_string_lossy() }
+    pub fn data(&self) -> &[u8] { &self.data }
+
+    /// Setup data if this registers an actor stream
+    pub fn actor_data(&self) -> Option<ActorStreamData> {
+        match self.stream_type() {
+            StreamType::Actor => wire().deserialize(&self.data).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn set_status(&mut self, status: i32) { self.status = status; }
+    pub fn set_origin(&mut self, sourceid: i32, streamid: i32) {
+        self.origin_sourceid = sourceid;
+        self.origin_streamid = streamid;
+    }
+    pub fn set_actor_data(&mut self, actor: &ActorStreamData) {
+        self.data.copy_from_slice(&wire().serialize(actor).unwrap());
+    }
+}
+
+impl StreamUnregister {
+    pub const WIRE_SIZE: usize = 4;
+
+    pub fn decode(payload: &[u8]) -> Result<StreamUnregister, DecodeError> {
+        if payload.len() != StreamUnregister::WIRE_SIZE {
+            return Err(DecodeError::InvalidPayload(MessageType::StreamUnregister,
+                format!("expected {} bytes, got {}", StreamUnregister::WIRE_SIZE, payload.len())));
+        }
+        wire().deserialize(payload)
+            .map_err(|err| DecodeError::InvalidPayload(MessageType::StreamUnregister, err.to_string()))
+    }
+}
+
+impl ServerInfo {
+    pub const WIRE_SIZE: usize = 4373;
+
+    pub fn decode(payload: &[u8]) -> Result<ServerInfo, DecodeError> {
+        if payload.len() != ServerInfo::WIRE_SIZE {
+            return Err(DecodeError::InvalidPayload(MessageType::Hello,
+                format!("expected {} bytes, got {}", ServerInfo::WIRE_SIZE, payload.len())));
+        }
+        wire().deserialize(payload)
+            .map_err(|err| DecodeError::InvalidPayload(MessageType::Hello, err.to_string()))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        wire().serialize(self).unwrap()
+    }
+
+
+    pub fn new(
+        version: ProtocolVersion,
+        terrain: &str,
+        server_name: &str,
+        has_password: bool,
+        info: &str,
+    ) -> ServerInfo {
+        ServerInfo {
+            protocol_version: FixedStr::new(version.as_str()),
+            terrain: FixedStr::new(terrain),
+            server_name: FixedStr::new(server_name),
+            has_password: has_password as u8,
+            info: FixedStr::new(info),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::STREAM_REG_SUCCESS;
+
+    fn decode(reg: &StreamRegister) -> Result<StreamRegister, DecodeError> {
+        StreamRegister::decode(MessageType::StreamRegister, &reg.encode())
+    }
+
+    fn actor(buffer_size: i32) -> StreamRegister {
+        let mut reg: StreamRegister = StreamRegister::new(StreamType::Actor, 7, 10, "agoras.truck");
+        reg.set_actor_data(&ActorStreamData { buffer_size, ..Default::default() });
+        reg
+    }
+
+    fn assert_rejected(reg: &StreamRegister, reason: &str) {
+        match decode(reg) {
+            Err(DecodeError::InvalidPayload(MessageType::StreamRegister, err)) =>
+                assert!(err.contains(reason), "{:?} does not mention {:?}", err, reason),
+            Err(err) => panic!("rejected for the wrong reason: {}", err),
+            Ok(_) => panic!("accepted a registration that should fail with {:?}", reason),
+        }
+    }
+
+    #[test]
+    fn known_stream_types_are_accepted() {
+        for stream_type in [StreamType::Character, StreamType::AiTraffic, StreamType::Chat] {
+            let reg: StreamRegister = decode(&StreamRegister::new(stream_type, 7, 10, "default")).unwrap();
+            assert_eq!(reg.stream_type(), stream_type);
+        }
+        assert_eq!(decode(&actor(1234)).unwrap().actor_data().unwrap().buffer_size, 1234);
+    }
+
+    #[test]
+    fn unknown_stream_type_is_rejected() {
+        assert_rejected(&StreamRegister::new(StreamType::Unknown(4), 7, 10, "default"), "unknown stream type 4");
+        assert_rejected(&StreamRegister::new(StreamType::Unknown(-1), 7, 10, "default"), "unknown stream type -1");
+    }
+
+    #[test]
+    fn name_with_a_slash_is_rejected() {
+        assert_rejected(&StreamRegister::new(StreamType::Character, 7, 10, "vehicles/truck"), "bad stream name");
+    }
+
+    #[test]
+    fn name_with_a_backslash_is_rejected() {
+        assert_rejected(&StreamRegister::new(StreamType::Character, 7, 10, "vehicles\\truck"), "bad stream name");
+    }
+
+    #[test]
+    fn name_with_dot_dot_is_rejected() {
+        assert_rejected(&StreamRegister::new(StreamType::Character, 7, 10, "..truck"), "bad stream name");
+        // A single dot is part of any file name
+        assert!(decode(&StreamRegister::new(StreamType::Character, 7, 10, "a.truck")).is_ok());
+    }
+
+    #[test]
+    fn actor_buffer_size_of_zero_or_less_is_rejected() {
+        assert_rejected(&actor(0), "bad actor buffer size 0");
+        assert_rejected(&actor(-1), "bad actor buffer size -1");
+    }
+
+    #[test]
+    fn actor_buffer_size_above_the_message_maximum_is_rejected() {
+        assert!(decode(&actor(RORNET_MAX_MESSAGE_LENGTH as i32)).is_ok());
+        assert_rejected(&actor(RORNET_MAX_MESSAGE_LENGTH as i32 + 1), "bad actor buffer size 8193");
+    }
+
+    #[test]
+    fn wrong_size_is_rejected() {
+        let payload: Vec<u8> = StreamRegister::new(StreamType::Character, 7, 10, "default").encode();
+        let err = StreamRegister::decode(MessageType::StreamRegister, &payload[..payload.len() - 1]).unwrap_err();
+        assert!(matches!(err, DecodeError::InvalidPayload(MessageType::StreamRegister, _)));
+    }
+
+    #[test]
+    fn stream_register_result_round_trips() {
+        let mut reg: StreamRegister = actor(1234);
+        reg.set_status(STREAM_REG_SUCCESS);
+        let payload: Vec<u8> = reg.encode();
+        assert_eq!(payload.len(), StreamRegister::WIRE_SIZE);
+
+        let decoded: StreamRegister = StreamRegister::decode(MessageType::StreamRegisterResult, &payload).unwrap();
+        assert_eq!(decoded.stream_type(), StreamType::Actor);
+        assert_eq!(decoded.status(), STREAM_REG_SUCCESS);
+        assert_eq!((decoded.origin_sourceid(), decoded.origin_streamid()), (7, 10));
+        assert_eq!(decoded.name(), "agoras.truck");
+        assert_eq!(decoded.actor_data().unwrap().buffer_size, 1234);
+        assert_eq!(decoded.encode(), payload);
+
+        // Errors name the command they came with
+        let err = StreamRegister::decode(MessageType::StreamRegisterResult, &payload[1..]).unwrap_err();
+        assert!(matches!(err, DecodeError::InvalidPayload(MessageType::StreamRegisterResult, _)));
+    }
+}
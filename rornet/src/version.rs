@@ -0,0 +1,161 @@
+use bincode::Options;
+use bytes::Bytes;
+
+use crate::{wire, EncodeError, DecodeError, FixedStr, Header, MessageType, Packet, RORNET_VERSION};
+
+/* Protocol versions. The header layout has been the same since RoRnet 2.x,
+   so the Hello can always be decoded; everything after it goes through the
+   version the client asked for. */
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ProtocolVersion {
+    V2_44,
+}
+
+// Every version spoken here, newest first
+pub const SUPPORTED_VERSIONS: &[ProtocolVersion] = &[ProtocolVersion::V2_44];
+
+impl ProtocolVersion {
+    pub fn newest() -> ProtocolVersion {
+        SUPPORTED_VERSIONS[0]
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProtocolVersion::V2_44 => RORNET_VERSION,
+        }
+    }
+
+    /// Picks the version a client asked for in its Hello, if we speak it
+    pub fn negotiate(hello: &str) -> Option<ProtocolVersion> {
+        SUPPORTED_VERSIONS.iter().copied().find(|version| version.as_str() == hello)
+    }
+
+    /// Reply to a Hello with a version we do not speak. Clients from
+    /// RoRnet 2.38 and earlier only understand the legacy wrong version
+    /// message, which carries the server version.
+    pub fn wrong_version_reply(hello: &str) -> Packet {
+        let is_legacy = hello.strip_prefix("RoRnet_2.")
+            .and_then(|minor| minor.parse::<u32>().ok())
+            .is_some_and(|minor| minor <= 38);
+        if is_legacy {
+            let server_version: FixedStr<20> = FixedStr::new(ProtocolVersion::newest().as_str());
+            Packet::Raw(MessageType::WrongVerLegacy, wire().serialize(&server_version).unwrap().into())
+        } else {
+            Packet::Raw(MessageType::WrongVer, Bytes::new())
+        }
+    }
+
+    /// Decodes a message from a client speaking this version
+    pub fn decode(&self, data: &Bytes) -> Result<(Header, Packet), DecodeError> {
+        match self {
+            ProtocolVersion::V2_44 => Packet::decode(data),
+        }
+    }
+
+    /// Encodes a message for a client speaking this version
+    pub fn encode(&self, packet: &Packet, source: i32, streamid: u32) -> Result<Bytes, EncodeError> {
+        match self {
+            ProtocolVersion::V2_44 => packet.encode(source, streamid),
+        }
+    }
+
+    /// Whether a message received from a client on this version can be
+    /// passed on unchanged to a client on `to`
+    pub fn can_relay(&self, to: ProtocolVersion, command: MessageType) -> bool {
+        same_layout(self.layout_revision(command), to.layout_revision(command))
+    }
+
+    // Revision of each message's payload layout. Bumped for a command when a
+    // new version changes its struct, which stops it being relayed between
+    // clients on either side of the change.
+    fn layout_revision(&self, command: MessageType) -> Option<u32> {
+        match (self, command) {
+            (_, MessageType::Unknown(_)) => None,
+            (ProtocolVersion::V2_44, _) => Some(1),
+        }
+    }
+}
+
+// Layouts without a revision are unknown and never match, not even each other
+fn same_layout(from: Option<u32>, to: Option<u32>) -> bool {
+    match (from, to) {
+        (Some(from), Some(to)) => from == to,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RORNET_HEADER_SIZE;
+
+    fn reply_payload(hello: &str) -> (MessageType, Bytes) {
+        let packet: Packet = ProtocolVersion::wrong_version_reply(hello);
+        let command: MessageType = packet.command();
+        let data: Bytes = packet.encode(0, 0).unwrap();
+        (command, data.slice(RORNET_HEADER_SIZE..))
+    }
+
+    #[test]
+    fn supported_version_is_accepted() {
+        assert_eq!(ProtocolVersion::negotiate(RORNET_VERSION), Some(ProtocolVersion::V2_44));
+        assert_eq!(ProtocolVersion::newest(), ProtocolVersion::V2_44);
+        assert_eq!(ProtocolVersion::V2_44.as_str(), "RoRnet_2.44");
+    }
+
+    #[test]
+    fn unknown_newer_version_gets_wrong_ver() {
+        for hello in ["RoRnet_2.45", "RoRnet_3.0", "RoRnet_2.44 ", "rornet_2.44", ""] {
+            assert_eq!(ProtocolVersion::negotiate(hello), None, "{:?}", hello);
+        }
+        assert_eq!(reply_payload("RoRnet_2.45"), (MessageType::WrongVer, Bytes::new()));
+        // Not a 2.x version number at all
+        assert_eq!(reply_payload("RoRnet_2.x").0, MessageType::WrongVer);
+        assert_eq!(reply_payload("hello").0, MessageType::WrongVer);
+    }
+
+    #[test]
+    fn legacy_client_gets_the_server_version() {
+        for hello in ["RoRnet_2.38", "RoRnet_2.35", "RoRnet_2.3"] {
+            let (command, payload) = reply_payload(hello);
+            assert_eq!(command, MessageType::WrongVerLegacy, "{:?}", hello);
+            // A char[20], NUL padded after the version
+            assert_eq!(payload.len(), 20);
+            assert_eq!(&payload[..RORNET_VERSION.len()], RORNET_VERSION.as_bytes());
+            assert!(payload[RORNET_VERSION.len()..].iter().all(|byte| *byte == 0));
+        }
+        assert_eq!(u32::from(MessageType::WrongVerLegacy), 1003);
+        // The first version with the current reply
+        assert_eq!(reply_payload("RoRnet_2.39").0, MessageType::WrongVer);
+    }
+
+    #[test]
+    fn same_version_relays_known_commands() {
+        let version: ProtocolVersion = ProtocolVersion::V2_44;
+        for command in [MessageType::StreamData, MessageType::StreamRegister, MessageType::Utf8Chat] {
+            assert!(version.can_relay(version, command), "{:?}", command);
+        }
+        assert!(!version.can_relay(version, MessageType::Unknown(9999)));
+    }
+
+    #[test]
+    fn layouts_relay_only_at_the_same_revision() {
+        assert!(same_layout(Some(1), Some(1)));
+        assert!(!same_layout(Some(1), Some(2)));
+        assert!(!same_layout(Some(2), Some(1)));
+        assert!(!same_layout(Some(1), None));
+        assert!(!same_layout(None, Some(1)));
+        assert!(!same_layout(None, None));
+    }
+
+    #[test]
+    fn decode_and_encode_follow_the_version() {
+        let version: ProtocolVersion = ProtocolVersion::V2_44;
+        let packet: Packet = Packet::Raw(MessageType::Utf8Chat, Bytes::from_static(b"hi"));
+        let data: Bytes = version.encode(&packet, 3, 4).unwrap();
+        assert_eq!(data, packet.encode(3, 4).unwrap());
+        let (head, _) = version.decode(&data).unwrap();
+        assert_eq!((head.command, head.source, head.streamid), (MessageType::Utf8Chat, 3, 4));
+    }
+}
@@ -0,0 +1,143 @@
+#!/usr/bin/env python3
+"""Regenerates the RoRnet layout fixtures in this directory.
+
+The structs below are copied by hand from RoRnet.h of the C++ ror-server
+(RoRnet_2.44). The header wraps them all in `#pragma pack(push, 1)`, so every
+struct here sets `_pack_ = 1` and the bytes carry no padding, same as what
+the C++ server puts on the wire with its memcpy based SendMessage. They are
+not captured from a real server, and cannot catch a mistake made in the copy
+beyond the sizes asserted in main().
+"""
+
+import ctypes as c
+import os
+
+MSG2_HELLO = 1025
+MSG2_USER_INFO = 1033
+MSG2_WELCOME = 1030
+MSG2_STREAM_REGISTER = 1041
+MSG2_STREAM_REGISTER_RESULT = 1042
+MSG2_STREAM_UNREGISTER = 1043
+
+RORNET_MAX_USERNAME_LEN = 40
+
+
+class Header(c.LittleEndianStructure):
+    _pack_ = 1
+    _fields_ = [
+        ("command", c.c_uint32),
+        ("source", c.c_int32),
+        ("streamid", c.c_uint32),
+        ("size", c.c_uint32),
+    ]
+
+
+class ServerInfo(c.LittleEndianStructure):
+    _pack_ = 1
+    _fields_ = [
+        ("protocolversion", c.c_char * 20),
+        ("terrain", c.c_char * 128),
+        ("servername", c.c_char * 128),
+        ("has_password", c.c_uint8),
+        ("info", c.c_char * 4096),
+    ]
+
+
+class UserInfo(c.LittleEndianStructure):
+    _pack_ = 1
+    _fields_ = [
+        ("uniqueid", c.c_uint32),
+        ("authstatus", c.c_int32),
+        ("slotnum", c.c_int32),
+        ("colournum", c.c_int32),
+        ("username", c.c_char * RORNET_MAX_USERNAME_LEN),
+        ("usertoken", c.c_char * 40),
+        ("serverpassword", c.c_char * 40),
+        ("language", c.c_char * 10),
+        ("clientname", c.c_char * 10),
+        ("clientversion", c.c_char * 25),
+        ("clientGUID", c.c_char * 40),
+        ("sessiontype", c.c_char * 10),
+        ("sessionoptions", c.c_char * 128),
+    ]
+
+
+class StreamRegister(c.LittleEndianStructure):
+    _pack_ = 1
+    _fields_ = [
+        ("type", c.c_int32),
+        ("status", c.c_int32),
+        ("origin_sourceid", c.c_int32),
+        ("origin_streamid", c.c_int32),
+        ("name", c.c_char * 128),
+        ("data", c.c_char * 128),
+    ]
+
+
+class ActorStreamRegister(c.LittleEndianStructure):
+    _pack_ = 1
+    _fields_ = [
+        ("type", c.c_int32),
+        ("status", c.c_int32),
+        ("origin_sourceid", c.c_int32),
+        ("origin_streamid", c.c_int32),
+        ("name", c.c_char * 128),
+        ("bufferSize", c.c_int32),
+        ("time", c.c_int32),
+        ("skin", c.c_char * 60),
+        ("sectionconfig", c.c_char * 60),
+    ]
+
+
+class StreamUnRegister(c.LittleEndianStructure):
+    _pack_ = 1
+    _fields_ = [
+        ("streamid", c.c_uint32),
+    ]
+
+
+def message(command, source, streamid, payload):
+    payload = bytes(payload)
+    return bytes(Header(command, source, streamid, len(payload))) + payload
+
+
+def write(name, data):
+    with open(os.path.join(os.path.dirname(__file__), name), "wb") as f:
+        f.write(data)
+
+
+def main():
+    assert c.sizeof(Header) == 16
+    assert c.sizeof(ServerInfo) == 4373
+    assert c.sizeof(UserInfo) == 359
+    assert c.sizeof(StreamRegister) == 272
+    assert c.sizeof(ActorStreamRegister) == 272
+
+    # Client side of the handshake, the version goes without its NUL
+    write("hello.bin", message(MSG2_HELLO, 0, 0, b"RoRnet_2.44"))
+
+    write("server_info.bin", message(MSG2_HELLO, 0, 0, ServerInfo(
+        b"RoRnet_2.44", b"simple2", b"Golden Server", 1, b"Welcome to the golden server!")))
+
+    user = UserInfo(
+        uniqueid=7, authstatus=2 | 8, slotnum=3, colournum=5,
+        username=b"golden_user", usertoken=b"0123456789abcdef",
+        serverpassword=b"hunter2", language=b"en_GB",
+        clientname=b"RoR", clientversion=b"2022.12",
+        clientGUID=b"5c8e9d2a-golden-guid", sessiontype=b"normal",
+        sessionoptions=b"")
+    write("user_info.bin", message(MSG2_USER_INFO, 0, 0, user))
+    write("welcome.bin", message(MSG2_WELCOME, 7, 0, user))
+
+    write("stream_register_actor.bin", message(MSG2_STREAM_REGISTER, 7, 10, ActorStreamRegister(
+        type=0, status=0, origin_sourceid=7, origin_streamid=10,
+        name=b"agoras.truck", bufferSize=1234, time=5678,
+        skin=b"default", sectionconfig=b"")))
+    write("stream_register_result.bin", message(MSG2_STREAM_REGISTER_RESULT, 7, 11, StreamRegister(
+        type=1, status=1, origin_sourceid=7, origin_streamid=11,
+        name=b"default", data=b"")))
+    write("stream_unregister.bin", message(MSG2_STREAM_UNREGISTER, 7, 10, StreamUnRegister(10)))
+
+
+if __name__ == "__main__":
+    main()
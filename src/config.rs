@@ -1,50 +1,98 @@
 mod print;
 mod default;
 
+use std::net::IpAddr;
+use std::ptr::addr_of;
+use std::sync::OnceLock;
+
 pub static mut CONF: Option<Config> = None;
 
+// Public ip found at startup, for when none is configured
+static DETECTED_IP: OnceLock<IpAddr> = OnceLock::new();
+
 pub enum ServerType {
     Lan,
     Inet,
     Auto,
 }
 
+// How the public ip is found when none is configured
+pub enum IpDetectMode {
+    Remote,     // ask the serverlist, then any fallback endpoints
+    Local,      // use the first public address found on a local interface
+}
+
+// Mirrors server.ini
 pub struct Config {
     s_server_name: String,
     s_terrain_name: String,
     s_public_password: String,
     s_ip_addr: String,
+    #[allow(dead_code)]            // no script engine loads it yet
     s_scriptname: String,
+    #[allow(dead_code)]            // no user authentication yet
     s_authfile: String,
     s_motdfile: String,
+    #[allow(dead_code)]            // no !rules command yet
     s_rulesfile: String,
     s_blacklistfile: String,
+    #[allow(dead_code)]            // no !owner command yet
     s_owner: String,
+    #[allow(dead_code)]            // no !website command yet
     s_website: String,
+    #[allow(dead_code)]            // no !irc command yet
     s_irc: String,
+    #[allow(dead_code)]            // no !voip command yet
     s_voip: String,
     s_serverlist_host: String,
-    s_serverlist_path: String,
+    s_serverlist_path: Vec<String>,
+    #[allow(dead_code)]            // nothing serves resources yet
     s_resourcedir: String,
 
+    // Address the sockets bind to, :: takes IPv4 and IPv6 clients alike
+    s_listen_addr: String,
     s_listen_port: u32,
+    s_ipv6_only: bool,
+    // Public IPv6 address registered with the serverlists next to s_ip_addr
+    s_ipv6_addr: String,
     s_max_clients: u32,
+    s_max_pending_clients: u32,
+    // Share of the pending pool one address (IPv6: one /64) may hold
+    s_max_pending_per_ip: u32,
     s_heartbeat_retry_count: u32,
     s_heartbeat_retry_seconds: u32,
     s_heartbeat_interval_sec: u32,
+    s_handshake_timeout_sec: u32,
+    s_idle_timeout_sec: u32,
+    s_tick_rate: u32,
+    s_shutdown_countdown_sec: u32,
 
     s_print_stats: bool,
+    #[allow(dead_code)]            // the server never daemonizes yet
     s_foreground: bool,
     s_show_version: bool,
     s_show_help: bool,
 
     // Vehicle spawn limits
     s_max_vehicles: usize,
+    #[allow(dead_code)]            // spawn rate limiting is not implemented yet
     s_spawn_interval_sec: i32,
+    #[allow(dead_code)]            // spawn rate limiting is not implemented yet
     s_max_spawn_rate:i32,
 
     s_server_mode: ServerType,
 
+    // Public ip detection
+    s_ip_detect_mode: IpDetectMode,
+    s_ip_detect_fallbacks: Vec<String>,
+    s_prefer_ipv6: bool,
+
+    // Serverlist HTTP client
+    s_http_connect_timeout_sec: u32,
+    s_http_timeout_sec: u32,
+    s_http_proxy: String,
+    s_http_ca_file: String,
+
     s_spamfilter_msg_interval_sec: i32,
     s_spamfilter_msg_count: i32,
     s_spamfilter_gag_duration_sec: i32,
@@ -64,18 +112,58 @@ impl Config {
     pub fn get_show_version(&self) -> bool { self.s_show_version }
     pub fn get_server_mode(&self) -> &ServerType { &self.s_server_mode }
     pub fn get_ip_addr(&self) -> &str { &self.s_ip_addr }
+    pub fn get_listen_addr(&self) -> &str { &self.s_listen_addr }
     pub fn get_listen_port(&self) -> &u32 { &self.s_listen_port }
+    pub fn get_ipv6_only(&self) -> bool { self.s_ipv6_only }
+    pub fn get_ipv6_addr(&self) -> &str { &self.s_ipv6_addr }
     pub fn get_server_name(&self) -> &str { &self.s_server_name }
     pub fn get_terrain_name(&self) -> &str { &self.s_terrain_name }
+    pub fn get_motdfile(&self) -> &str { &self.s_motdfile }
+    pub fn get_blacklistfile(&self) -> &str { &self.s_blacklistfile }
     pub fn get_max_clients(&self) -> &u32 { &self.s_max_clients }
+    pub fn get_max_pending_clients(&self) -> &u32 { &self.s_max_pending_clients }
+    pub fn get_max_pending_per_ip(&self) -> &u32 { &self.s_max_pending_per_ip }
+    pub fn get_max_vehicles(&self) -> &usize { &self.s_max_vehicles }
     pub fn get_public_pw(&self) -> &str { &self.s_public_password }
-    pub fn get_serverlist_path(&self) -> &str { &self.s_serverlist_path }
+    pub fn get_heartbeat_retry_count(&self) -> &u32 { &self.s_heartbeat_retry_count }
+    pub fn get_heartbeat_retry_seconds(&self) -> &u32 { &self.s_heartbeat_retry_seconds }
+    pub fn get_heartbeat_interval_sec(&self) -> &u32 { &self.s_heartbeat_interval_sec }
+    pub fn get_handshake_timeout_sec(&self) -> &u32 { &self.s_handshake_timeout_sec }
+    pub fn get_idle_timeout_sec(&self) -> &u32 { &self.s_idle_timeout_sec }
+    pub fn get_tick_rate(&self) -> &u32 { &self.s_tick_rate }
+    pub fn get_shutdown_countdown_sec(&self) -> &u32 { &self.s_shutdown_countdown_sec }
+    pub fn get_print_stats(&self) -> bool { self.s_print_stats }
+    pub fn get_spamfilter_msg_interval_sec(&self) -> &i32 { &self.s_spamfilter_msg_interval_sec }
+    pub fn get_spamfilter_msg_count(&self) -> &i32 { &self.s_spamfilter_msg_count }
+    pub fn get_spamfilter_gag_duration_sec(&self) -> &i32 { &self.s_spamfilter_gag_duration_sec }
+    pub fn get_serverlist_paths(&self) -> &[String] { &self.s_serverlist_path }
     pub fn get_serverlist_host(&self) -> &str { &self.s_serverlist_host }
+    pub fn get_ip_detect_mode(&self) -> &IpDetectMode { &self.s_ip_detect_mode }
+    pub fn get_ip_detect_fallbacks(&self) -> &[String] { &self.s_ip_detect_fallbacks }
+    pub fn get_prefer_ipv6(&self) -> bool { self.s_prefer_ipv6 }
+    pub fn get_http_connect_timeout_sec(&self) -> &u32 { &self.s_http_connect_timeout_sec }
+    pub fn get_http_timeout_sec(&self) -> &u32 { &self.s_http_timeout_sec }
+    pub fn get_http_proxy(&self) -> &str { &self.s_http_proxy }
+    pub fn get_http_ca_file(&self) -> &str { &self.s_http_ca_file }
 
-    pub fn set_ip_addr(&mut self, ip: &str) {
-        self.s_ip_addr = ip.to_string();
+    /// Public ip to register with: the configured one, or else the one
+    /// detected at startup
+    pub fn get_public_ip(&self) -> Option<IpAddr> {
+        self.s_ip_addr.parse::<IpAddr>().ok()
+            .filter(|ip| !ip.is_unspecified())
+            .or_else(|| DETECTED_IP.get().copied())
     }
-    
+
     pub fn is_public(&self) -> bool { !&self.get_public_pw().is_empty() }
 
-}
\ No newline at end of file
+}
+
+/// Returns the global config. Must only be called after `Config::build`.
+pub fn get() -> &'static Config {
+    unsafe { (*addr_of!(CONF)).as_ref().unwrap() }
+}
+
+/// Remembers the public ip detected at startup. Only the first call counts.
+pub fn set_detected_ip(ip: IpAddr) {
+    let _ = DETECTED_IP.set(ip);
+}
@@ -1,4 +1,4 @@
-use super::{Config, ServerType};
+use super::{Config, IpDetectMode, ServerType};
 
 impl Default for Config {
     fn default() -> Config {
@@ -16,16 +16,34 @@ impl Default for Config {
             s_irc: Default::default(),
             s_voip: Default::default(),
             s_serverlist_host: String::from("https://api.rigsofrods.org"),
-            s_serverlist_path: String::from("https://api.rigsofrods.org"),
+            s_serverlist_path: vec![String::from("https://api.rigsofrods.org")],
             s_resourcedir: String::from(""),
 
             s_server_mode: ServerType::Inet,
+
+            s_ip_detect_mode: IpDetectMode::Remote,
+            s_ip_detect_fallbacks: Vec::new(),
+            s_prefer_ipv6: false,
+
+            s_http_connect_timeout_sec: 10,
+            s_http_timeout_sec: 30,
+            s_http_proxy: Default::default(),
+            s_http_ca_file: Default::default(),
             s_ip_addr: String::from("0.0.0.0"),
-            s_listen_port: 0,
+            s_listen_addr: String::from("::"),
+            s_listen_port: 12456,
+            s_ipv6_only: false,
+            s_ipv6_addr: Default::default(),
             s_max_clients: 16,
+            s_max_pending_clients: 8,
+            s_max_pending_per_ip: 2,
             s_heartbeat_retry_count: 5,
             s_heartbeat_retry_seconds: 15,
             s_heartbeat_interval_sec: 60,
+            s_handshake_timeout_sec: 10,
+            s_idle_timeout_sec: 60,
+            s_tick_rate: 20,
+            s_shutdown_countdown_sec: 0,
 
             s_print_stats: false,
             s_foreground: false,
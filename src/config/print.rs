@@ -17,7 +17,7 @@ impl Config {
         \n
          -password <password>         Private server password\n
          -ip <ip>                     Public IP address to register with.\n
-         -port <port>                 Port to use (defaults to random 12000-12500)\n
+         -port <port>                 Port to use (defaults to 12456)\n
          -verbosity {{0-5}}             Sets displayed log verbosity\n
          -log-verbosity {{0-5}}         Sets file log verbositylog verbosity\n
                                       levels available to verbosity and logverbosity:\n
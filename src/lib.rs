@@ -0,0 +1,6 @@
+pub mod config;
+pub mod logger;
+pub mod master_server;
+pub mod listener;
+pub mod net;
+pub mod script;
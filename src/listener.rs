@@ -1,195 +1,1518 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::time::Duration;
-use std::str;
 use tokio::signal;
-use tokio::sync::{broadcast, Mutex as TokioMutex};
-use tokio::net::UdpSocket;
-use tokio::time::{self, Interval, interval};
-use tokio_stream::wrappers::IntervalStream;
-use tokio_stream::StreamExt;
+use serde_json::{Value, json};
+use tokio::sync::{broadcast, mpsc, watch, Mutex as TokioMutex, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+use sha1::{Digest, Sha1};
+use socket2::{Domain, Protocol, Socket, Type};
+use subtle::ConstantTimeEq;
 
 use crate::net::{
     self,
-    MessageType,
+    Bytes,
+    BytesMut,
     Header,
-    ServerInfo,
-    RORNET_VERSION
+    MessageType,
+    Packet,
+    ProtocolVersion,
+    UserInfo,
+    RORNET_HEADER_SIZE,
+    RORNET_MAX_MESSAGE_LENGTH,
+    RORNET_MAX_PEERS,
+    AUTH_NONE,
+    STREAM_REG_ERROR,
+    StreamRegister,
+    StreamType,
 };
 
-use crate::config::Config;
+use crate::config::{self, Config};
 use crate::logger::{
     self,
     LogLevel
 };
+use crate::script::Script;
+
+mod blacklist;
+mod spam_filter;
+mod stats;
+
+use blacklist::Blacklist;
+use spam_filter::SpamFilter;
+pub use stats::Stats;
+
+// Largest datagram a client can send, a header plus the biggest payload
+const MAX_DATAGRAM_SIZE: usize = RORNET_HEADER_SIZE + RORNET_MAX_MESSAGE_LENGTH as usize;
+// Received datagrams are packed back to back into buffers of this size
+const RECV_POOL_SIZE: usize = 64 * MAX_DATAGRAM_SIZE;
+// Messages waiting to go out to one client. A client that lets its queue
+// fill up loses discardable stream data first, and is dropped after that.
+const OUTBOUND_QUEUE_LEN: usize = 256;
+// How long queued messages get to go out when the server shuts down
+const FLUSH_DEADLINE: Duration = Duration::from_secs(3);
+// Seconds left at which players are warned during a shutdown countdown
+const COUNTDOWN_WARNINGS: [u32; 6] = [60, 30, 10, 5, 3, 1];
+// How often the stats are logged when print_stats is on
+const STATS_PRINT_INTERVAL: Duration = Duration::from_secs(60);
+// Streams of any kind one client may have registered at once
+const MAX_STREAMS: usize = 64;
+// Wrong passwords an address may send before its Hellos are ignored for a while
+const PASSWORD_ATTEMPTS: u32 = 3;
+const PASSWORD_THROTTLE: Duration = Duration::from_secs(60);
 
 enum ClientState {
     Pending,
     Connected
 }
 
+/// How messages get to a client: as datagrams on the shared UDP socket, or
+/// over its own TCP connection
+#[derive(Clone)]
+pub enum Transport {
+    Udp(Arc<UdpSocket>),
+    Tcp(Arc<TcpConnection>),
+}
+
+impl Transport {
+    // Ends the connection of a client the server dropped. UDP has nothing
+    // to close.
+    fn close(&self) {
+        if let Transport::Tcp(conn) = self {
+            conn.closed.notify_one();
+        }
+    }
+
+    // Whether both are the same socket or connection. A client's messages
+    // only count when they come in the way it connected.
+    fn is(&self, other: &Transport) -> bool {
+        match (self, other) {
+            (Transport::Udp(socket), Transport::Udp(other)) => Arc::ptr_eq(socket, other),
+            (Transport::Tcp(conn), Transport::Tcp(other)) => Arc::ptr_eq(conn, other),
+            _ => false,
+        }
+    }
+}
+
+pub struct TcpConnection {
+    // Written to the socket by the connection's writer task
+    queue: mpsc::Sender<Bytes>,
+    // Wakes the connection's reader when the server drops the client
+    closed: Notify,
+}
+
 pub struct Client {
     state: ClientState,
     ipaddr: std::net::SocketAddr,
+    transport: Transport,
+    // Outbound messages, sent by a task of the client's own so that nobody
+    // waits for a slow client
+    queue: mpsc::Sender<Bytes>,
+    // Negotiated from the Hello, picks the codec for this client
+    version: ProtocolVersion,
+    // Filled in from the client's UserInfo once it leaves Pending
+    user: UserInfo,
+    // When the client entered its current state, for the handshake timeout
+    since: Instant,
+    // Last valid message, for the idle timeout
+    last_seen: Instant,
+    // Streams the client registered, by stream id. It may only send on these.
+    streams: HashMap<u32, StreamRegister>,
+    spam: SpamFilter,
+}
+
+impl Client {
+    pub fn user(&self) -> &UserInfo { &self.user }
+    pub fn ipaddr(&self) -> std::net::SocketAddr { self.ipaddr }
+    pub fn version(&self) -> ProtocolVersion { self.version }
+    pub fn transport(&self) -> &Transport { &self.transport }
+}
+
+// A TCP connection's place among the handshakes, given back when dropped
+struct HandshakePermit {
+    _permit: OwnedSemaphorePermit,
+    key: IpAddr,
+    by_ip: Arc<Mutex<HashMap<IpAddr, u32>>>,
+}
+
+impl Drop for HandshakePermit {
+    fn drop(&mut self) {
+        let mut by_ip = self.by_ip.lock().unwrap();
+        if let Some(count) = by_ip.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                by_ip.remove(&self.key);
+            }
+        }
+    }
+}
+
+// Wrong password attempts from one address
+struct PasswordFailures {
+    count: u32,
+    last: Instant,
+}
+
+impl PasswordFailures {
+    fn throttled(&self, now: Instant) -> bool {
+        self.count >= PASSWORD_ATTEMPTS && now - self.last < PASSWORD_THROTTLE
+    }
 }
 
 pub struct Listener {
-    ip: String,
-    port: String,
-    tick: u64,
     clients: Arc<TokioMutex<HashMap<std::net::SocketAddr , Client>>>,
+    // User list reported to the serverlists with every heartbeat
+    users: watch::Sender<Value>,
+    // Message of the day, sent in ServerInfo and as chat after joining
+    motd: RwLock<String>,
+    // Addresses turned away with Banned
+    blacklist: RwLock<Blacklist>,
+    // SHA1 of the server password as clients send it, None without one
+    password_hash: Option<String>,
+    // Keyed by throttle_key
+    password_failures: TokioMutex<HashMap<IpAddr, PasswordFailures>>,
+    // TCP connections that did not send their Hello yet, so that connections
+    // which never do cannot pile up ahead of the pending pool
+    tcp_handshakes: Arc<Semaphore>,
+    // The same connections counted by throttle_key, so that one host cannot
+    // take all of them
+    tcp_handshakes_by_ip: Arc<Mutex<HashMap<IpAddr, u32>>>,
+    // Unique id handed to the next client that joins
+    next_uid: AtomicU32,
+    // Fires whenever the serverlists are due a heartbeat
+    heartbeats: broadcast::Sender<()>,
+    scripts: Mutex<Vec<Box<dyn Script>>>,
+    stats: Arc<Stats>,
+    // Clients whose queue overflowed or whose connection failed, removed on
+    // the next tick
+    failed: Mutex<HashSet<std::net::SocketAddr>>,
+    // Cleared once shutdown starts, no new clients after that
+    accepting: AtomicBool,
+    // Fires when a shutdown countdown ran out
+    countdown_over: Notify,
+    // Turns true when the server goes down, for the serverlists to
+    // unregister and connections to close
+    stopping: watch::Sender<bool>,
+    // Every writer task holds a clone, so the receiver sees the channel
+    // close once all queues went out
+    writers_done: Mutex<Option<mpsc::Sender<()>>>,
+    writers_flushed: TokioMutex<mpsc::Receiver<()>>,
 }
 
 impl Listener {
     pub fn new() -> Listener {
+        let (writers_done, writers_flushed) = mpsc::channel(1);
         Listener {
-            ip: "0.0.0.0".to_string(),
-            port: "12456".to_string(),
-            tick: 64,
-            clients: Arc::new(TokioMutex::new(HashMap::new()))
+            clients: Arc::new(TokioMutex::new(HashMap::new())),
+            users: watch::channel(json!([])).0,
+            motd: RwLock::new(load_motd()),
+            blacklist: RwLock::new(Blacklist::load(config::get().get_blacklistfile())),
+            password_hash: hash_password(config::get().get_public_pw()),
+            password_failures: TokioMutex::new(HashMap::new()),
+            tcp_handshakes: Arc::new(Semaphore::new(*config::get().get_max_pending_clients() as usize)),
+            tcp_handshakes_by_ip: Arc::new(Mutex::new(HashMap::new())),
+            next_uid: AtomicU32::new(1),
+            heartbeats: broadcast::channel(1).0,
+            scripts: Mutex::new(Vec::new()),
+            stats: Arc::new(Stats::new()),
+            failed: Mutex::new(HashSet::new()),
+            accepting: AtomicBool::new(true),
+            countdown_over: Notify::new(),
+            stopping: watch::channel(false).0,
+            writers_done: Mutex::new(Some(writers_done)),
+            writers_flushed: TokioMutex::new(writers_flushed),
         }
     }
-    pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let addr = format!("{}:{}", self.ip, self.port);
 
-        let sock = UdpSocket::bind(&addr).await?;
-        logger::log(LogLevel::Info, &format!("Server listening on {}", addr));
+    /// Reads the MOTD file again, for changes to apply without a restart
+    pub fn reload_motd(&self) {
+        *self.motd.write().unwrap() = load_motd();
+        logger::log(LogLevel::Info, "Reloaded message of the day");
+    }
+
+    /// Reads the blacklist file again and drops clients that are banned now
+    pub async fn reload_blacklist(&self) {
+        let blacklist: Blacklist = Blacklist::load(config::get().get_blacklistfile());
+        logger::log(LogLevel::Info, &format!("Reloaded blacklist, {} entries", blacklist.len()));
+        *self.blacklist.write().unwrap() = blacklist;
+
+        let mut clients = self.clients.lock().await;
+        let banned: Vec<std::net::SocketAddr> = clients.keys()
+            .copied()
+            .filter(|addr| self.is_banned(addr.ip()))
+            .collect();
+        for addr in banned {
+            self.remove_client(&mut clients, addr, "banned");
+        }
+    }
 
-        let sock: Arc<TokioMutex<UdpSocket>> = Arc::new(TokioMutex::new(sock));
-        let signal_sock: Arc<TokioMutex<UdpSocket>> = sock.clone();
+    fn is_banned(&self, ip: IpAddr) -> bool {
+        self.blacklist.read().unwrap().is_banned(ip)
+    }
 
-        let sigint = signal::ctrl_c();
-        tokio::pin!(sigint);
+    /// Subscribes to the list of connected users, as sent in serverlist heartbeats
+    pub fn subscribe_users(&self) -> watch::Receiver<Value> {
+        self.users.subscribe()
+    }
 
-        // setup tick rate - todo not finished
-        let tick_interval = Duration::from_millis(100);
-        let interval = interval(tick_interval);
+    /// Subscribes to the tick's signal that a serverlist heartbeat is due
+    pub fn subscribe_heartbeats(&self) -> broadcast::Receiver<()> {
+        self.heartbeats.subscribe()
+    }
 
-        let tick_stream = IntervalStream::new(interval);
-        let mut tick_stream = tick_stream.fuse();
+    /// Subscribes to the server going down, which is when the serverlists
+    /// should unregister
+    pub fn subscribe_shutdown(&self) -> watch::Receiver<bool> {
+        self.stopping.subscribe()
+    }
+
+    /// Adds a script to be called on every tick
+    pub fn add_script(&self, script: Box<dyn Script>) {
+        self.scripts.lock().unwrap().push(script);
+    }
+
+    pub fn stats(&self) -> &Stats { &self.stats }
+
+    // Clients that joined, against the slots for players
+    fn is_full(&self, clients: &HashMap<std::net::SocketAddr, Client>) -> bool {
+        let max_clients: usize = (*config::get().get_max_clients()).min(RORNET_MAX_PEERS) as usize;
+        let connected: usize = clients.values()
+            .filter(|client| matches!(client.state, ClientState::Connected))
+            .count();
+        connected >= max_clients
+    }
+
+    // Handshakes in progress, against their own pool so that clients which
+    // never finish cannot take the slots of players. One address only gets
+    // a share of the pool, so that it cannot keep everyone else out.
+    fn is_pending_full(&self, clients: &HashMap<std::net::SocketAddr, Client>, ip: IpAddr) -> bool {
+        let conf: &Config = config::get();
+        let key: IpAddr = throttle_key(ip);
+        let (pending, from_ip): (usize, usize) = clients.values()
+            .filter(|client| matches!(client.state, ClientState::Pending))
+            .fold((0, 0), |(pending, from_ip), client|
+                (pending + 1, from_ip + (throttle_key(client.ipaddr.ip()) == key) as usize));
+        pending >= *conf.get_max_pending_clients() as usize
+            || from_ip >= *conf.get_max_pending_per_ip() as usize
+    }
+
+    // Takes a place among the TCP handshakes, None if there is none left
+    // overall or for this address
+    fn start_tcp_handshake(&self, ip: IpAddr) -> Option<HandshakePermit> {
+        let key: IpAddr = throttle_key(ip);
+        let mut by_ip = self.tcp_handshakes_by_ip.lock().unwrap();
+        if by_ip.get(&key).copied().unwrap_or(0) >= *config::get().get_max_pending_per_ip() {
+            return None;
+        }
+        let permit: OwnedSemaphorePermit = self.tcp_handshakes.clone().try_acquire_owned().ok()?;
+        *by_ip.entry(key).or_insert(0) += 1;
+        Some(HandshakePermit {
+            _permit: permit,
+            key,
+            by_ip: self.tcp_handshakes_by_ip.clone(),
+        })
+    }
+
+    // Rebuilds the heartbeat user list from the connected clients
+    fn publish_users(&self, clients: &HashMap<std::net::SocketAddr, Client>) {
+        let user_list: Vec<Value> = clients.values()
+            .filter(|client| matches!(client.state, ClientState::Connected))
+            .map(|client| json!({
+                "is_admin": client.user.is_admin(),
+                "is_mod": client.user.is_mod(),
+                "is_ranked": client.user.is_ranked(),
+                "is_bot": client.user.is_bot(),
+                "username": client.user.username(),
+                "ip_address": client.ipaddr.ip().to_string(),
+                "client_id": client.user.uniqueid(),
+            }))
+            .collect();
+        self.users.send_replace(Value::Array(user_list));
+    }
+    /// Serves clients until SIGINT or SIGTERM, then disconnects all of them
+    pub async fn run(self: Arc<Self>) -> Result<(), Box<dyn std::error::Error>> {
+        let result = self.clone().serve().await;
+        // Whatever ended the server, the serverlists must not wait for it
+        self.stopping.send_replace(true);
+        result
+    }
+
+    async fn serve(self: Arc<Self>) -> Result<(), Box<dyn std::error::Error>> {
+        let conf: &Config = config::get();
+        let ip: IpAddr = conf.get_listen_addr().parse()
+            .map_err(|_| format!("Listen address {} is not an IP address", conf.get_listen_addr()))?;
+        let port: u16 = u16::try_from(*conf.get_listen_port())
+            .map_err(|_| format!("Listen port {} is out of range", conf.get_listen_port()))?;
+        let addr = std::net::SocketAddr::new(ip, port);
+
+        let (sock, tcp, addr) = match bind(addr, conf.get_ipv6_only()) {
+            Ok((sock, tcp)) => (sock, tcp, addr),
+            // Hosts without IPv6 still get to serve IPv4
+            Err(err) if ip == Ipv6Addr::UNSPECIFIED && !conf.get_ipv6_only() => {
+                logger::log(LogLevel::Warn, &format!("Could not listen on {}: {}, trying IPv4 only", addr, err));
+                let addr = std::net::SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
+                let (sock, tcp) = bind(addr, false)?;
+                (sock, tcp, addr)
+            },
+            Err(err) => return Err(err.into()),
+        };
+        let dual_stack: &str = if addr.is_ipv6() && !conf.get_ipv6_only() { ", IPv4 and IPv6" } else { "" };
+        logger::log(LogLevel::Info, &format!("Server listening on {} (UDP and TCP{})", addr, dual_stack));
+
+        // Receiving and every client's writer task use the socket at once
+        let sock: Arc<UdpSocket> = Arc::new(sock);
+        let udp: Transport = Transport::Udp(sock.clone());
+
+        let mut shutdown_signal = ShutdownSignal::new()?;
+        let mut reload_signal = ReloadSignal::new()?;
+        let mut countdown: Option<JoinHandle<()>> = None;
+
+        // The tick runs on its own task, receiving never waits for it
+        let ticks = tokio::spawn(self.clone().run_ticks());
+
+        let mut recv_buf: BytesMut = BytesMut::with_capacity(RECV_POOL_SIZE);
         loop {
             tokio::select! {
-                _ = &mut sigint => {
-                    println!("Received Ctrl+C, shutting down...");
+                _ = shutdown_signal.recv() => {
+                    // A second request skips whatever is left of the countdown
+                    if !self.accepting.swap(false, Ordering::Relaxed) {
+                        logger::log(LogLevel::Info, "Shutting down now");
+                        break;
+                    }
+                    let secs: u32 = *config::get().get_shutdown_countdown_sec();
+                    if secs == 0 {
+                        logger::log(LogLevel::Info, "Shutting down...");
+                        break;
+                    }
+                    logger::log(LogLevel::Info, &format!("Shutting down in {} seconds...", secs));
+                    countdown = Some(tokio::spawn(self.clone().count_down(secs)));
+                }
+                _ = self.countdown_over.notified() => {
                     break;
                 }
-                // _ = tick_stream.next() => {
-                //    self.process_tick(&sock, &sender).await?;
-                // }
-                client_data = self.receive_client_data(&sock) => {
-                    if let Ok((data, src_addr)) = client_data {
-                        self.process_client_data(&sock, src_addr, &data).await?;
+                _ = reload_signal.recv() => {
+                    self.reload_motd();
+                    self.reload_blacklist().await;
+                }
+                client_data = self.receive_client_data(&sock, &mut recv_buf) => {
+                    match client_data {
+                        Ok((data, src_addr)) => self.process_client_data(&udp, src_addr, data).await,
+                        // Some platforms report unreachable clients on the next receive
+                        Err(err) => logger::log(LogLevel::Debug, &format!("Could not receive: {}", err)),
+                    }
+                }
+                accepted = tcp.accept() => {
+                    match accepted {
+                        Ok((stream, src_addr)) => {
+                            if !self.accepting.load(Ordering::Relaxed) {
+                                continue;
+                            }
+                            let src_addr = canonical_addr(src_addr);
+                            match self.start_tcp_handshake(src_addr.ip()) {
+                                Some(handshake) => {
+                                    tokio::spawn(self.clone().serve_tcp(stream, src_addr, handshake));
+                                },
+                                None => logger::log(LogLevel::Debug,
+                                    &format!("Too many TCP handshakes, closing connection from {}", src_addr)),
+                            }
+                        },
+                        Err(err) => {
+                            logger::log(LogLevel::Warn, &format!("Could not accept TCP connection: {}", err));
+                        },
                     }
                 }
             }
         }
+        ticks.abort();
+        if let Some(countdown) = countdown {
+            countdown.abort();
+        }
+        self.accepting.store(false, Ordering::Relaxed);
+        self.disconnect_all().await;
         Ok(())
     }
 
-    pub async fn process_tick(&self, socket: &Arc<TokioMutex<UdpSocket>>, sender: &broadcast::Sender<()>) -> Result<(), Box<dyn std::error::Error>> {
-        sender.send(())?;
+    // Warns everyone a few times before the server goes down
+    async fn count_down(self: Arc<Self>, secs: u32) {
+        let end: Instant = Instant::now() + Duration::from_secs(secs as u64);
+        self.warn_shutdown(secs).await;
+        for remaining in COUNTDOWN_WARNINGS.into_iter().filter(|remaining| *remaining < secs) {
+            time::sleep_until(end - Duration::from_secs(remaining as u64)).await;
+            self.warn_shutdown(remaining).await;
+        }
+        time::sleep_until(end).await;
+        self.countdown_over.notify_one();
+    }
 
-        // Perform tick-based processing here
+    async fn warn_shutdown(&self, remaining: u32) {
+        let warning: Packet = net::server_chat(&format!("The server shuts down in {} second{}",
+            remaining, if remaining == 1 { "" } else { "s" }));
+        let clients = self.clients.lock().await;
+        self.send_to_all(&clients, &warning, -1, u32::MAX, |_| true);
+    }
 
-        Ok(())
+    // Tells every client the server is going away and disconnects it, then
+    // gives the queues a moment to go out
+    async fn disconnect_all(&self) {
+        {
+            let mut clients = self.clients.lock().await;
+            let notice: Packet = net::server_chat("The server is shutting down");
+            self.send_to_all(&clients, &notice, -1, u32::MAX, |_| true);
+            // A UserLeave with its own id disconnects a client
+            let leave: Packet = Packet::Raw(MessageType::UserLeave, Bytes::from_static(b"server shutting down"));
+            for client in clients.values().filter(|client| matches!(client.state, ClientState::Connected)) {
+                self.send(client, &leave, client.user.uniqueid() as i32, 0);
+            }
+            for (_, client) in clients.drain() {
+                client.transport.close();
+            }
+            self.publish_users(&clients);
+        }
+        // Closes connections that never became clients as well
+        self.stopping.send_replace(true);
+
+        self.writers_done.lock().unwrap().take();
+        let mut writers_flushed = self.writers_flushed.lock().await;
+        if time::timeout(FLUSH_DEADLINE, writers_flushed.recv()).await.is_err() {
+            logger::log(LogLevel::Warn, "Not every client got its last messages before the deadline");
+        }
     }
 
-    pub async fn process_message(&self, socket: &Arc<TokioMutex<UdpSocket>>) -> Result<(), Box<dyn std::error::Error>> {
-        // Perform message processing here
+    // Runs process_tick at the configured rate. Ticks that fall behind are
+    // skipped rather than run back to back, and counted as late.
+    async fn run_ticks(self: Arc<Self>) {
+        let conf: &Config = config::get();
+        let period: Duration = Duration::from_secs(1) / (*conf.get_tick_rate()).max(1);
+        let heartbeat_interval = Duration::from_secs(*conf.get_heartbeat_interval_sec() as u64);
 
-        Ok(())
+        let mut ticks: Interval = time::interval(period);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut last_tick: Instant = Instant::now();
+        let mut last_heartbeat: Instant = Instant::now();
+        let mut last_print: Instant = Instant::now();
+        loop {
+            let deadline: Instant = ticks.tick().await;
+            let now: Instant = Instant::now();
+            self.stats.record_tick(now - deadline, period);
+
+            self.process_tick(now - last_tick).await;
+            last_tick = now;
+
+            if now - last_heartbeat >= heartbeat_interval {
+                last_heartbeat = now;
+                // Nobody listening just means there are no serverlists
+                let _ = self.heartbeats.send(());
+            }
+            if conf.get_print_stats() && now - last_print >= STATS_PRINT_INTERVAL {
+                last_print = now;
+                logger::log(LogLevel::Info, &format!("Stats: {}", self.stats));
+            }
+        }
     }
 
-    pub async fn receive_client_data(&self, socket: &Arc<TokioMutex<UdpSocket>>) -> Result<(Vec<u8>, std::net::SocketAddr), Box<dyn std::error::Error>> {
-        // buf is the size of Header + max size of message
-        let mut buf = [0u8; (16 + net::RORNET_MAX_MESSAGE_LENGTH as usize)];
-        let (size, src_addr) = socket.lock().await.recv_from(&mut buf).await?;
-        let data = buf[..size].to_vec();
-        Ok((data, src_addr))
+    /// One server tick, `dt` after the previous one. Normally run by the
+    /// tick loop, public for driving the listener without it.
+    pub async fn process_tick(&self, dt: Duration) {
+        self.sweep_timeouts().await;
+        {
+            let now: Instant = Instant::now();
+            let mut clients = self.clients.lock().await;
+            for client in clients.values_mut() {
+                client.spam.decay(now);
+            }
+            let connected: usize = clients.values()
+                .filter(|client| matches!(client.state, ClientState::Connected))
+                .count();
+            self.stats.sample_clients(connected);
+        }
+        for script in self.scripts.lock().unwrap().iter_mut() {
+            script.frame_step(dt);
+        }
     }
 
-    pub async fn process_client_data(&self, socket: &Arc<TokioMutex<UdpSocket>>, src_addr: std::net::SocketAddr, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
-        // Handle client data here
-        // For example, update client state, send responses, etc.
-        // Make sure client sent a packet at least the size of header
-        if data.len() < 16 {
-            logger::log(LogLevel::Debug,
-                &format!("Client {} sent a non-RoRnet packet. Ignoring...", &src_addr));
-            return Ok(());
+    // Receives the next datagram into the pooled buffer and splits it off as
+    // its own reference counted slice, without copying it
+    pub async fn receive_client_data(&self, socket: &UdpSocket, buf: &mut BytesMut) -> Result<(Bytes, std::net::SocketAddr), Box<dyn std::error::Error>> {
+        // Room for the largest possible message. Reserving reuses the memory
+        // of earlier datagrams once nothing refers to them anymore.
+        if buf.capacity() < MAX_DATAGRAM_SIZE {
+            buf.reserve(RECV_POOL_SIZE);
+        }
+        let (_, src_addr) = socket.recv_buf_from(buf).await?;
+        Ok((buf.split().freeze(), canonical_addr(src_addr)))
+    }
+
+    // Reads the messages of one TCP connection and feeds them through the
+    // same state machine as datagrams. The connection lives as long as its
+    // client does. Until its first message it holds a handshake permit and
+    // has the handshake timeout to send it.
+    async fn serve_tcp(
+        self: Arc<Self>,
+        stream: TcpStream,
+        src_addr: std::net::SocketAddr,
+        handshake: HandshakePermit
+    ) {
+        logger::log(LogLevel::Debug, &format!("New TCP connection from {}", src_addr));
+        let (mut reader, writer) = stream.into_split();
+        let (queue, outbound) = mpsc::channel(OUTBOUND_QUEUE_LEN);
+        tokio::spawn(write_tcp(writer, outbound, src_addr, self.stats.clone(), self.writer_guard()));
+        let conn: Arc<TcpConnection> = Arc::new(TcpConnection {
+            queue,
+            closed: Notify::new(),
+        });
+        let transport: Transport = Transport::Tcp(conn.clone());
+        let mut stopping: watch::Receiver<bool> = self.stopping.subscribe();
+        let mut handshake: Option<HandshakePermit> = Some(handshake);
+        let hello_deadline: Instant = Instant::now()
+            + Duration::from_secs(*config::get().get_handshake_timeout_sec() as u64);
+
+        loop {
+            let frame = tokio::select! {
+                frame = read_frame(&mut reader) => frame,
+                _ = conn.closed.notified() => break,
+                _ = stopping.wait_for(|stopping| *stopping) => break,
+                _ = time::sleep_until(hello_deadline), if handshake.is_some() => {
+                    logger::log(LogLevel::Debug,
+                        &format!("TCP connection {} sent no Hello in time, closing connection...", src_addr));
+                    break;
+                },
+            };
+            let data: Bytes = match frame {
+                Ok(Some(data)) => data,
+                Ok(None) => break,
+                Err(err) => {
+                    logger::log(LogLevel::Debug,
+                        &format!("Client {} broke the TCP framing ({}), closing connection...", src_addr, err));
+                    break;
+                },
+            };
+            self.process_client_data(&transport, src_addr, data).await;
+            // Turned away or dropped by the state machine
+            if !self.is_client(&*self.clients.lock().await, src_addr, &transport) {
+                break;
+            }
+            // From here on the pending pool and its timeout cover it
+            handshake = None;
+        }
+        let mut clients = self.clients.lock().await;
+        // A UDP client may use the same address and port, it stays
+        if self.is_client(&clients, src_addr, &transport) {
+            self.remove_client(&mut clients, src_addr, "disconnected");
+        }
+        // The writer sends what is still queued and closes the connection
+        // once the last handle on its queue is gone
+    }
+
+    // Whether the client at `src_addr` is the one connected through `transport`
+    fn is_client(
+        &self,
+        clients: &HashMap<std::net::SocketAddr, Client>,
+        src_addr: std::net::SocketAddr,
+        transport: &Transport
+    ) -> bool {
+        clients.get(&src_addr).is_some_and(|client| client.transport.is(transport))
+    }
+
+    // Drops clients stuck in the handshake, gone silent or unreachable
+    async fn sweep_timeouts(&self) {
+        let conf: &Config = config::get();
+        let handshake_timeout = Duration::from_secs(*conf.get_handshake_timeout_sec() as u64);
+        let idle_timeout = Duration::from_secs(*conf.get_idle_timeout_sec() as u64);
+        let now: Instant = Instant::now();
+
+        let mut clients = self.clients.lock().await;
+        let expired: Vec<(std::net::SocketAddr, &str)> = clients.values()
+            .filter_map(|client| match client.state {
+                ClientState::Pending if now - client.since > handshake_timeout =>
+                    Some((client.ipaddr, "handshake timed out")),
+                ClientState::Connected if now - client.last_seen > idle_timeout =>
+                    Some((client.ipaddr, "timed out")),
+                _ => None,
+            })
+            .collect();
+        for (addr, reason) in expired {
+            self.remove_client(&mut clients, addr, reason);
+        }
+        let failed: HashSet<std::net::SocketAddr> = std::mem::take(&mut *self.failed.lock().unwrap());
+        for addr in failed {
+            self.remove_client(&mut clients, addr, "connection lost");
+        }
+        drop(clients);
+
+        self.password_failures.lock().await
+            .retain(|_, failures| now - failures.last < PASSWORD_THROTTLE);
+    }
+
+    // Checks the password a client sent in its UserInfo. Every failure is
+    // counted against the client's address.
+    async fn check_password(&self, user: &UserInfo, src_addr: std::net::SocketAddr) -> bool {
+        let hash: &str = match &self.password_hash {
+            Some(hash) => hash,
+            None => return true,
+        };
+        if bool::from(user.serverpassword().ct_eq(hash.as_bytes())) {
+            return true;
+        }
+        let now: Instant = Instant::now();
+        let mut failures = self.password_failures.lock().await;
+        let entry = failures.entry(throttle_key(src_addr.ip()))
+            .or_insert(PasswordFailures { count: 0, last: now });
+        if now - entry.last >= PASSWORD_THROTTLE {
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.last = now;
+        false
+    }
+
+    async fn is_throttled(&self, ip: IpAddr) -> bool {
+        self.password_failures.lock().await
+            .get(&throttle_key(ip))
+            .is_some_and(|failures| failures.throttled(Instant::now()))
+    }
+
+    // Forgets a client and closes its connection. Everyone else is told it
+    // left if it had joined.
+    fn remove_client(
+        &self,
+        clients: &mut HashMap<std::net::SocketAddr, Client>,
+        src_addr: std::net::SocketAddr,
+        reason: &str
+    ) {
+        let client: Client = match clients.remove(&src_addr) {
+            Some(client) => client,
+            None => return,
+        };
+        client.transport.close();
+        logger::log(LogLevel::Debug, &format!("Client {} removed: {}", src_addr, reason));
+        if !matches!(client.state, ClientState::Connected) {
+            return;
         }
-        let (head_raw, payload_raw) = (&data[..16], &data[16..]);
-        let head = match bincode::deserialize::<Header>(head_raw) {
+        self.publish_users(clients);
+
+        let leave: Packet = Packet::Raw(MessageType::UserLeave, Bytes::copy_from_slice(reason.as_bytes()));
+        self.broadcast(clients, src_addr, &leave, client.user.uniqueid() as i32, 0);
+    }
+
+    // Runs one message through the client state machine. Whatever goes wrong
+    // only concerns this client (or the ones it sends to) and is dealt with
+    // here, the caller just moves on to the next message.
+    pub async fn process_client_data(&self, transport: &Transport, src_addr: std::net::SocketAddr, data: Bytes) {
+        self.stats.record_in(data.len());
+        let mut clients = self.clients.lock().await;
+
+        // Only fully validated messages make it past this point. Known clients
+        // are decoded with their own protocol version, a Hello with any.
+        let decoded = match clients.get(&src_addr) {
+            // A datagram with the address of a TCP client (or the other way
+            // round) is not from that client, whatever its source says
+            Some(client) if !client.transport.is(transport) => {
+                logger::log(LogLevel::Debug,
+                    &format!("Client {} sent a message outside its own connection. Ignoring...", &src_addr));
+                return;
+            },
+            Some(client) => client.version.decode(&data),
+            None => Packet::decode(&data),
+        };
+        let (head, packet) = match decoded {
             Ok(res) => res,
-            Err(_) => {
+            Err(err) => {
                 logger::log(LogLevel::Debug,
-                &format!("Client {} sent a non-RoRnet packet. Ignoring...", &src_addr));
-                return Ok(());
+                    &format!("Client {} sent an invalid RoRnet packet ({}). Ignoring...", &src_addr, err));
+                return;
             },
         };
-
         // Add or update client in the clients list
-        let mut clients = self.clients.lock().await;
         if let Some(client) = clients.get_mut(&src_addr) {
-            // Update existing client
+            client.last_seen = Instant::now();
             match client.state {
                 ClientState::Pending => {
-                    if head.command == MessageType::UserInfo {
-                        client.state = ClientState::Connected;
-                        logger::log(LogLevel::Debug, 
-                            &format!("Client {} moved from pending to connected", src_addr));
-                        // Update the client struct with stuff idk
+                    if let Packet::UserInfo(_, user) = packet {
+                        self.join_client(&mut clients, src_addr, *user).await;
                     } else {
                         logger::log(LogLevel::Debug,
                             &format!("Client {} did not respond with a UserInfo packet, dropping connection...", src_addr));
-                        clients.remove(&src_addr);
+                        self.remove_client(&mut clients, src_addr, "no UserInfo");
                     }
                 },
                 ClientState::Connected => {
-                    // Actually do the client stuff in game here
+                    self.handle_game_message(&mut clients, src_addr, head, packet);
                 },
             }
         } else {
+            if !self.accepting.load(Ordering::Relaxed) {
+                logger::log(LogLevel::Debug,
+                    &format!("Ignoring client {}, the server is shutting down", src_addr));
+                return;
+            }
+            if self.is_throttled(src_addr.ip()).await {
+                logger::log(LogLevel::Debug,
+                    &format!("Ignoring client {}, too many wrong passwords", src_addr));
+                return;
+            }
             // Make sure the client sends HELLO as the first packet
-            if head.command != MessageType::Hello {
-                logger::log(LogLevel::Warn, 
-                    &format!("Client {} did not send a HELLO packet as its first packet", src_addr));
-                self.send(socket, MessageType::WrongVer, 0, 0, vec![], src_addr).await?;
-                return Ok(());
-            }
-            if buf_to_str(&payload_raw) != RORNET_VERSION {
-                logger::log(LogLevel::Warn, 
-                    &format!("Client {} had wrong protocol version", src_addr));
-                self.send(socket, MessageType::WrongVer, 0, 0, vec![], src_addr).await?;
-                return Ok(());
+            let hello: String = match packet {
+                Packet::Hello(hello) => hello,
+                _ => {
+                    logger::log(LogLevel::Warn, 
+                        &format!("Client {} did not send a HELLO packet as its first packet", src_addr));
+                    let reply: Packet = Packet::Raw(MessageType::WrongVer, Bytes::new());
+                    self.reply(transport, src_addr, &reply);
+                    return;
+                }
+            };
+            let version: ProtocolVersion = match ProtocolVersion::negotiate(&hello) {
+                Some(version) => version,
+                None => {
+                    logger::log(LogLevel::Warn, 
+                        &format!("Client {} had unsupported protocol version {:?}", src_addr, hello));
+                    let reply: Packet = ProtocolVersion::wrong_version_reply(&hello);
+                    self.reply(transport, src_addr, &reply);
+                    return;
+                }
+            };
+            if self.is_banned(src_addr.ip()) {
+                logger::log(LogLevel::Info, &format!("Turning away banned client {}", src_addr));
+                let reply: Packet = Packet::Raw(MessageType::Banned, Bytes::new());
+                self.reply(transport, src_addr, &reply);
+                return;
+            }
+            if self.is_full(&clients) || self.is_pending_full(&clients, src_addr.ip()) {
+                logger::log(LogLevel::Info,
+                    &format!("Server is full, turning away client {}", src_addr));
+                let reply: Packet = Packet::Raw(MessageType::Full, Bytes::new());
+                self.reply(transport, src_addr, &reply);
+                return;
             }
             // Creates a new client in the Pending state
-            clients.insert(src_addr, Client { state: ClientState::Pending, ipaddr: src_addr });
-            logger::log(LogLevel::Debug, &format!("New client in pending: {}", src_addr));
+            clients.insert(src_addr, Client {
+                state: ClientState::Pending,
+                ipaddr: src_addr,
+                transport: transport.clone(),
+                queue: self.open_queue(transport, src_addr),
+                version,
+                user: UserInfo::default(),
+                since: Instant::now(),
+                last_seen: Instant::now(),
+                streams: HashMap::new(),
+                spam: SpamFilter::new(),
+            });
+            logger::log(LogLevel::Debug,
+                &format!("New client in pending: {} ({})", src_addr, version.as_str()));
             // Sends a ServerInfo packet back to the client
-            let s_info: Vec<u8> = ServerInfo::build_packet();
-            self.send(socket, MessageType::Hello, 0, 0, s_info, src_addr).await?;
+            let motd: String = self.motd.read().unwrap().clone();
+            let s_info: Packet = Packet::ServerInfo(Box::new(net::server_info(version, &motd)));
+            self.send(&clients[&src_addr], &s_info, 0, 0);
         }
-        Ok(())
     }
 
-    // Sends a payload with specified command. Make payload using build_packet
-    // functions in net.rs
-    pub async fn send(
+    // Moves a client that sent its UserInfo from Pending to Connected, unless
+    // its password is wrong or the server filled up in the meantime
+    async fn join_client(
         &self,
-        socket: &Arc<TokioMutex<UdpSocket>>,
-        command: MessageType,
+        clients: &mut HashMap<std::net::SocketAddr, Client>,
+        src_addr: std::net::SocketAddr,
+        mut user: UserInfo
+    ) {
+        if !self.check_password(&user, src_addr).await {
+            logger::log(LogLevel::Info,
+                &format!("Client {} ({}) sent a wrong server password, dropping connection...",
+                    src_addr, user.username()));
+            // The queue outlives its client long enough to send the reply
+            if let Some(client) = clients.remove(&src_addr) {
+                self.send(&client, &Packet::Raw(MessageType::WrongPw, Bytes::new()), 0, 0);
+            }
+            return;
+        }
+        // Others may have finished their handshake first
+        if self.is_full(clients) {
+            logger::log(LogLevel::Info,
+                &format!("Server is full, turning away client {}", src_addr));
+            if let Some(client) = clients.remove(&src_addr) {
+                self.send(&client, &Packet::Raw(MessageType::Full, Bytes::new()), 0, 0);
+            }
+            return;
+        }
+        // Nobody else needs to see it from here on
+        user.clear_serverpassword();
+
+        // Whatever the client claims, the server decides who it is
+        let uid: u32 = self.next_uid.fetch_add(1, Ordering::Relaxed);
+        let (slot, colour): (i32, i32) = free_slot_and_colour(clients);
+        user.set_uniqueid(uid);
+        user.set_slotnum(slot);
+        user.set_colournum(colour);
+        user.set_authstatus(AUTH_NONE);
+        logger::log(LogLevel::Debug,
+            &format!("Client {} ({}) moved from pending to connected as user {} in slot {}",
+                src_addr, user.username(), uid, slot));
+
+        let newcomer: &Client = match clients.get(&src_addr) {
+            Some(client) => client,
+            None => return,
+        };
+        let welcome: Packet = Packet::UserInfo(MessageType::Welcome, Box::new(user.clone()));
+        self.send(newcomer, &welcome, uid as i32, 0);
+
+        // Introduce everyone already playing, then announce the newcomer
+        for other in clients.values().filter(|other| matches!(other.state, ClientState::Connected)) {
+            let info: Packet = Packet::UserInfo(MessageType::UserInfo, Box::new(other.user.clone()));
+            self.send(newcomer, &info, other.user.uniqueid() as i32, 0);
+        }
+        for other in clients.values().filter(|other| matches!(other.state, ClientState::Connected)) {
+            for (streamid, reg) in &other.streams {
+                let register: Packet = Packet::StreamRegister(MessageType::StreamRegister, Box::new(reg.clone()));
+                self.send(newcomer, &register, other.user.uniqueid() as i32, *streamid);
+            }
+        }
+        let join: Packet = Packet::UserInfo(MessageType::UserJoin, Box::new(user.clone()));
+        self.broadcast(clients, src_addr, &join, uid as i32, 0);
+
+        if let Some(client) = clients.get_mut(&src_addr) {
+            client.user = user;
+            client.state = ClientState::Connected;
+            client.since = Instant::now();
+        }
+        self.publish_users(clients);
+
+        // Greet the newcomer with the MOTD, a chat line per line
+        let motd: String = self.motd.read().unwrap().clone();
+        for line in motd.lines() {
+            self.send(&clients[&src_addr], &net::server_chat(line), -1, u32::MAX);
+        }
+    }
+
+    // Handles a message from a client that joined the game. Stream traffic
+    // is only passed on for streams the client registered itself, always
+    // under its own id.
+    fn handle_game_message(
+        &self,
+        clients: &mut HashMap<std::net::SocketAddr, Client>,
+        src_addr: std::net::SocketAddr,
+        head: Header,
+        packet: Packet
+    ) {
+        let (uid, version): (i32, ProtocolVersion) = match clients.get(&src_addr) {
+            Some(client) => (client.user.uniqueid() as i32, client.version),
+            None => return,
+        };
+        match packet {
+            Packet::StreamRegister(MessageType::StreamRegister, mut reg) => {
+                let client: &mut Client = clients.get_mut(&src_addr).unwrap();
+                if let Err(reason) = check_stream_register(client, head.streamid, &reg) {
+                    logger::log(LogLevel::Info,
+                        &format!("Client {} ({}) cannot register stream {} ({:?}): {}",
+                            src_addr, client.user.username(), head.streamid, reg.name(), reason));
+                    reg.set_status(STREAM_REG_ERROR);
+                    let reply: Packet = Packet::StreamRegister(MessageType::StreamRegisterResult, reg);
+                    self.send(client, &reply, uid, head.streamid);
+                    return;
+                }
+                reg.set_origin(uid, head.streamid as i32);
+                logger::log(LogLevel::Debug,
+                    &format!("Client {} registered {:?} stream {} ({:?})",
+                        src_addr, reg.stream_type(), head.streamid, reg.name()));
+                client.streams.insert(head.streamid, (*reg).clone());
+
+                let register: Packet = Packet::StreamRegister(MessageType::StreamRegister, reg);
+                self.relay(clients, src_addr, &register, uid, head.streamid);
+            },
+            Packet::StreamRegister(MessageType::StreamRegisterResult, reg) => {
+                // Goes back to the owner of the stream, if it still has it
+                let owner: Option<&Client> = clients.values().find(|client|
+                    matches!(client.state, ClientState::Connected)
+                        && client.user.uniqueid() as i32 == reg.origin_sourceid()
+                        && client.streams.contains_key(&(reg.origin_streamid() as u32)));
+                if let Some(owner) = owner {
+                    if version.can_relay(owner.version, MessageType::StreamRegisterResult) {
+                        let result: Packet = Packet::StreamRegister(MessageType::StreamRegisterResult, reg);
+                        self.send(owner, &result, uid, head.streamid);
+                    }
+                }
+            },
+            Packet::StreamUnregister(_) => {
+                let client: &mut Client = clients.get_mut(&src_addr).unwrap();
+                if client.streams.remove(&head.streamid).is_some() {
+                    self.relay(clients, src_addr, &packet, uid, head.streamid);
+                }
+            },
+            Packet::Raw(MessageType::StreamData | MessageType::StreamDataDiscardable, _) => {
+                if !clients[&src_addr].streams.contains_key(&head.streamid) {
+                    logger::log(LogLevel::Debug,
+                        &format!("Client {} sent data on stream {} it does not own, ignoring...",
+                            src_addr, head.streamid));
+                    return;
+                }
+                self.relay(clients, src_addr, &packet, uid, head.streamid);
+            },
+            Packet::Raw(MessageType::Utf8Chat, _) => {
+                let client: &mut Client = clients.get_mut(&src_addr).unwrap();
+                let was_gagged: bool = client.spam.is_gagged(Instant::now());
+                if !client.spam.allow(Instant::now()) {
+                    if !was_gagged {
+                        logger::log(LogLevel::Info,
+                            &format!("Client {} ({}) gagged for spamming", src_addr, client.user.username()));
+                        let warning: Packet = net::server_chat(&format!(
+                            "You are gagged for {} seconds for spamming",
+                            config::get().get_spamfilter_gag_duration_sec()));
+                        self.send(client, &warning, -1, u32::MAX);
+                    }
+                    return;
+                }
+                // Chat goes to everyone, the sender sees it once the server echoes it
+                self.send_to_all(clients, &packet, uid, head.streamid,
+                    |client| version.can_relay(client.version, MessageType::Utf8Chat));
+            },
+            // A client quitting says so, no need to wait for the idle timeout
+            Packet::Raw(MessageType::UserLeave, _) => {
+                self.remove_client(clients, src_addr, "left");
+            },
+            Packet::Raw(MessageType::Unknown(command), _) => {
+                logger::log(LogLevel::Debug,
+                    &format!("Client {} sent unknown command {}, ignoring...", src_addr, command));
+            },
+            other => {
+                logger::log(LogLevel::Debug,
+                    &format!("Client {} sent unexpected {:?}, ignoring...", src_addr, other.command()));
+            },
+        }
+    }
+
+    // Sends a packet to every connected client but one
+    fn broadcast(
+        &self,
+        clients: &HashMap<std::net::SocketAddr, Client>,
+        except: std::net::SocketAddr,
+        packet: &Packet,
+        source: i32,
+        streamid: u32
+    ) {
+        self.send_to_all(clients, packet, source, streamid, |client| client.ipaddr != except);
+    }
+
+    // Passes what a client sent on to every other client whose protocol
+    // version has the same layout for it
+    fn relay(
+        &self,
+        clients: &HashMap<std::net::SocketAddr, Client>,
+        from: std::net::SocketAddr,
+        packet: &Packet,
+        source: i32,
+        streamid: u32
+    ) {
+        let version: ProtocolVersion = match clients.get(&from) {
+            Some(client) => client.version,
+            None => return,
+        };
+        self.send_to_all(clients, packet, source, streamid, |client|
+            client.ipaddr != from && version.can_relay(client.version, packet.command()));
+    }
+
+    // Sends a packet to the connected clients `receives` picks. It is encoded
+    // once per protocol version in use, not once per client. Failures only
+    // concern the receiving client and are logged against it.
+    fn send_to_all(
+        &self,
+        clients: &HashMap<std::net::SocketAddr, Client>,
+        packet: &Packet,
         source: i32,
         streamid: u32,
-        mut payload: Vec<u8>,
-        dest: std::net::SocketAddr
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut msg: Vec<u8> = Header::build_packet(command, source, streamid, payload.len() as u32);
-        msg.append(&mut payload);
-        socket.lock().await.send_to(&msg, dest).await?;
-        Ok(())
+        receives: impl Fn(&Client) -> bool
+    ) {
+        let discardable: bool = packet.command() == MessageType::StreamDataDiscardable;
+        let mut encoded: Vec<(ProtocolVersion, Bytes)> = Vec::new();
+        let receivers = clients.values()
+            .filter(|client| matches!(client.state, ClientState::Connected) && receives(client));
+        for client in receivers {
+            let msg: Bytes = match encoded.iter().find(|(version, _)| *version == client.version) {
+                Some((_, msg)) => msg.clone(),
+                None => match client.version.encode(packet, source, streamid) {
+                    Ok(msg) => {
+                        encoded.push((client.version, msg.clone()));
+                        msg
+                    },
+                    Err(err) => {
+                        logger::log(LogLevel::Warn,
+                            &format!("Could not encode {:?} for {}: {}", packet.command(), client.version.as_str(), err));
+                        continue;
+                    },
+                },
+            };
+            self.push(client, msg, discardable);
+        }
+    }
+
+    // Encodes a packet for the client's protocol version and queues it with
+    // the given source and stream
+    pub fn send(&self, client: &Client, packet: &Packet, source: i32, streamid: u32) {
+        match client.version.encode(packet, source, streamid) {
+            Ok(msg) => self.push(client, msg, false),
+            Err(err) => self.client_failed(client.ipaddr,
+                &format!("could not encode {:?} for it ({})", packet.command(), err)),
+        }
+    }
+
+    // Queues an already encoded message. Messages going to several clients
+    // are encoded once and the same buffer is queued for every one of them.
+    // Never waits: a client that cannot take the message is dropped on the
+    // next tick, unless the message was only discardable stream data.
+    fn push(&self, client: &Client, msg: Bytes, discardable: bool) {
+        match client.queue.try_send(msg) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) if discardable => self.stats.record_dropped(),
+            Err(TrySendError::Full(_)) => self.client_failed(client.ipaddr, "cannot keep up with its traffic"),
+            Err(TrySendError::Closed(_)) => self.client_failed(client.ipaddr, "connection lost"),
+        }
+    }
+
+    // Logs a problem with a client and has it removed on the next tick. Its
+    // traffic must not hold up anyone else, so nothing is done right away.
+    // Messages to it until then fail the same way and are not logged again.
+    fn client_failed(&self, addr: std::net::SocketAddr, reason: &str) {
+        if self.failed.lock().unwrap().insert(addr) {
+            logger::log(LogLevel::Info, &format!("Client {}: {}, dropping connection...", addr, reason));
+        }
+    }
+
+    // Answers a peer that is not a client, with the newest protocol version.
+    // Datagrams the socket cannot take right away are not worth waiting for.
+    fn reply(&self, transport: &Transport, dest: std::net::SocketAddr, packet: &Packet) {
+        let msg: Bytes = match ProtocolVersion::newest().encode(packet, 0, 0) {
+            Ok(msg) => msg,
+            Err(err) => {
+                logger::log(LogLevel::Warn, &format!("Could not encode {:?} for {}: {}", packet.command(), dest, err));
+                return;
+            },
+        };
+        match transport {
+            Transport::Udp(socket) => match socket.try_send_to(&msg, wire_addr(socket, dest)) {
+                Ok(_) => self.stats.record_out(msg.len()),
+                Err(err) => logger::log(LogLevel::Debug, &format!("Could not reply to {}: {}", dest, err)),
+            },
+            Transport::Tcp(conn) => {
+                let _ = conn.queue.try_send(msg);
+            },
+        }
+    }
+
+    // Held by a writer task for as long as it runs, see writers_done
+    fn writer_guard(&self) -> Option<mpsc::Sender<()>> {
+        self.writers_done.lock().unwrap().clone()
+    }
+
+    // Outbound queue for a new client. UDP clients get a writer task of their
+    // own, TCP clients share the one of their connection.
+    fn open_queue(&self, transport: &Transport, dest: std::net::SocketAddr) -> mpsc::Sender<Bytes> {
+        match transport {
+            Transport::Udp(socket) => {
+                let (queue, outbound) = mpsc::channel(OUTBOUND_QUEUE_LEN);
+                tokio::spawn(write_udp(socket.clone(), dest, outbound, self.stats.clone(), self.writer_guard()));
+                queue
+            },
+            Transport::Tcp(conn) => conn.queue.clone(),
+        }
+    }
+}
+
+impl Default for Listener {
+    fn default() -> Listener {
+        Listener::new()
+    }
+}
+
+// Binds the UDP socket and the TCP listener. Unless ipv6_only is set, an
+// IPv6 address takes IPv4 clients as well.
+fn bind(addr: std::net::SocketAddr, ipv6_only: bool) -> io::Result<(UdpSocket, TcpListener)> {
+    let udp: Socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    let tcp: Socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        udp.set_only_v6(ipv6_only)?;
+        tcp.set_only_v6(ipv6_only)?;
+    }
+    // Same as TcpListener::bind, restarts do not wait for old connections
+    #[cfg(unix)]
+    tcp.set_reuse_address(true)?;
+    for socket in [&udp, &tcp] {
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+    }
+    tcp.listen(1024)?;
+    Ok((UdpSocket::from_std(udp.into())?, TcpListener::from_std(tcp.into())?))
+}
+
+// IPv4 clients of a dual-stack socket show up with v4-mapped IPv6
+// addresses. As plain IPv4 they look the same in keys, bans and logs
+// whichever socket they came in on.
+fn canonical_addr(addr: std::net::SocketAddr) -> std::net::SocketAddr {
+    std::net::SocketAddr::new(canonical_ip(addr.ip()), addr.port())
+}
+
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+// What wrong passwords are counted against. An IPv6 host usually has a
+// whole /64 to pick addresses from, so that is what gets throttled.
+fn throttle_key(ip: IpAddr) -> IpAddr {
+    match canonical_ip(ip) {
+        IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !(u128::MAX >> 64))),
+        v4 => v4,
+    }
+}
+
+// Where to send to a client as the socket's family wants it, the reverse
+// of canonical_addr
+fn wire_addr(socket: &UdpSocket, dest: std::net::SocketAddr) -> std::net::SocketAddr {
+    match (socket.local_addr(), dest) {
+        (Ok(local), std::net::SocketAddr::V4(v4)) if local.is_ipv6() =>
+            std::net::SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+        _ => dest,
+    }
+}
+
+// Whether a client may register a stream with this id
+fn check_stream_register(client: &Client, streamid: u32, reg: &StreamRegister) -> Result<(), String> {
+    if client.streams.contains_key(&streamid) {
+        return Err("stream id already in use".to_string());
+    }
+    if client.streams.len() >= MAX_STREAMS {
+        return Err(format!("more than {} streams", MAX_STREAMS));
+    }
+    if let StreamType::Actor = reg.stream_type() {
+        let max_vehicles: usize = *config::get().get_max_vehicles();
+        let actors: usize = client.streams.values()
+            .filter(|other| matches!(other.stream_type(), StreamType::Actor))
+            .count();
+        if actors >= max_vehicles {
+            return Err(format!("vehicle limit of {} reached", max_vehicles));
+        }
+    }
+    Ok(())
+}
+
+// Lowest slot and colour numbers no connected client has
+fn free_slot_and_colour(clients: &HashMap<std::net::SocketAddr, Client>) -> (i32, i32) {
+    let connected: Vec<&UserInfo> = clients.values()
+        .filter(|client| matches!(client.state, ClientState::Connected))
+        .map(|client| &client.user)
+        .collect();
+    let slot: i32 = (0..RORNET_MAX_PEERS as i32)
+        .find(|slot| connected.iter().all(|user| user.slotnum() != *slot))
+        .unwrap_or(0);
+    let colour: i32 = (0..RORNET_MAX_PEERS as i32)
+        .find(|colour| connected.iter().all(|user| user.colournum() != *colour))
+        .unwrap_or(0);
+    (slot, colour)
+}
+
+// Clients send the uppercase hex SHA1 of the password, not the password
+fn hash_password(password: &str) -> Option<String> {
+    if password.is_empty() {
+        return None;
+    }
+    Some(format!("{:X}", Sha1::digest(password.as_bytes())))
+}
+
+// Reads one message off a TCP stream: the header, then as much payload as it
+// announces. None once the client closed the connection between messages.
+async fn read_frame(reader: &mut OwnedReadHalf) -> io::Result<Option<Bytes>> {
+    let mut head = [0u8; RORNET_HEADER_SIZE];
+    let mut filled: usize = 0;
+    while filled < RORNET_HEADER_SIZE {
+        match reader.read(&mut head[filled..]).await? {
+            0 if filled == 0 => return Ok(None),
+            // Closed halfway through a header
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            read => filled += read,
+        }
+    }
+    let size: usize = Packet::frame_size(&head)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+
+    let mut frame: BytesMut = BytesMut::with_capacity(RORNET_HEADER_SIZE + size);
+    frame.extend_from_slice(&head);
+    frame.resize(RORNET_HEADER_SIZE + size, 0);
+    reader.read_exact(&mut frame[RORNET_HEADER_SIZE..]).await?;
+    Ok(Some(frame.freeze()))
+}
+
+// Sends a UDP client's queue to it, until the client is gone or the socket
+// refuses to send to it
+async fn write_udp(
+    socket: Arc<UdpSocket>,
+    dest: std::net::SocketAddr,
+    mut outbound: mpsc::Receiver<Bytes>,
+    stats: Arc<Stats>,
+    _guard: Option<mpsc::Sender<()>>
+) {
+    let wire_dest: std::net::SocketAddr = wire_addr(&socket, dest);
+    while let Some(msg) = outbound.recv().await {
+        if let Err(err) = socket.send_to(&msg, wire_dest).await {
+            logger::log(LogLevel::Debug, &format!("Could not send to client {}: {}", dest, err));
+            return;
+        }
+        stats.record_out(msg.len());
+    }
+}
+
+// Writes a TCP connection's queue to it. Closes the connection once the
+// queue is empty and nothing can add to it anymore.
+async fn write_tcp(
+    mut writer: OwnedWriteHalf,
+    mut outbound: mpsc::Receiver<Bytes>,
+    dest: std::net::SocketAddr,
+    stats: Arc<Stats>,
+    _guard: Option<mpsc::Sender<()>>
+) {
+    while let Some(msg) = outbound.recv().await {
+        if let Err(err) = writer.write_all(&msg).await {
+            logger::log(LogLevel::Debug, &format!("Could not send to client {}: {}", dest, err));
+            return;
+        }
+        stats.record_out(msg.len());
+    }
+    let _ = writer.shutdown().await;
+}
+
+// Reads the MOTD file. A missing file just means there is no MOTD.
+fn load_motd() -> String {
+    let path: &str = config::get().get_motdfile();
+    match fs::read_to_string(path) {
+        Ok(motd) => motd,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => {
+            logger::log(LogLevel::Warn, &format!("Could not read MOTD file {}: {}", path, err));
+            String::new()
+        }
+    }
+}
+
+// SIGINT (Ctrl+C) or SIGTERM asks the server to shut down. Both are
+// registered up front, so a signal arriving while the run loop is busy
+// elsewhere is still seen.
+struct ShutdownSignal {
+    #[cfg(unix)]
+    interrupt: signal::unix::Signal,
+    #[cfg(unix)]
+    terminate: signal::unix::Signal,
+    #[cfg(not(unix))]
+    ctrl_c: signal::windows::CtrlC,
+}
+
+impl ShutdownSignal {
+    fn new() -> io::Result<ShutdownSignal> {
+        Ok(ShutdownSignal {
+            #[cfg(unix)]
+            interrupt: signal::unix::signal(signal::unix::SignalKind::interrupt())?,
+            #[cfg(unix)]
+            terminate: signal::unix::signal(signal::unix::SignalKind::terminate())?,
+            #[cfg(not(unix))]
+            ctrl_c: signal::windows::ctrl_c()?,
+        })
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = self.interrupt.recv() => (),
+            _ = self.terminate.recv() => (),
+        }
+        #[cfg(not(unix))]
+        self.ctrl_c.recv().await;
+    }
+}
+
+// SIGHUP asks for a reload of files like the MOTD. Platforms without it
+// simply never get a reload request.
+struct ReloadSignal {
+    #[cfg(unix)]
+    hangup: signal::unix::Signal,
+}
+
+impl ReloadSignal {
+    fn new() -> io::Result<ReloadSignal> {
+        Ok(ReloadSignal {
+            #[cfg(unix)]
+            hangup: signal::unix::signal(signal::unix::SignalKind::hangup())?,
+        })
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        self.hangup.recv().await;
+        #[cfg(not(unix))]
+        std::future::pending::<()>().await;
     }
 }
 
-fn buf_to_str(buf: &[u8]) -> &str {
-    str::from_utf8(buf).unwrap()
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use std::sync::Once;
+
+    use super::*;
+
+    fn setup() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            Config::build();
+        });
+    }
+
+    // A connected pair: the client's end, and the half the server reads from
+    async fn tcp_pair() -> (TcpStream, OwnedReadHalf) {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client: TcpStream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server.into_split().0)
+    }
+
+    fn chat(text: &str) -> Bytes {
+        Packet::Raw(MessageType::Utf8Chat, Bytes::copy_from_slice(text.as_bytes())).encode(0, 0).unwrap()
+    }
+
+    #[tokio::test]
+    async fn read_frame_joins_a_message_split_across_reads() {
+        let (mut client, mut reader) = tcp_pair().await;
+        let first: Bytes = chat("hello");
+        let second: Bytes = chat("world");
+        // Split inside the header, then inside the payload of the second message
+        client.write_all(&first[..5]).await.unwrap();
+        time::sleep(Duration::from_millis(20)).await;
+        client.write_all(&first[5..]).await.unwrap();
+        client.write_all(&second[..RORNET_HEADER_SIZE + 2]).await.unwrap();
+        time::sleep(Duration::from_millis(20)).await;
+        client.write_all(&second[RORNET_HEADER_SIZE + 2..]).await.unwrap();
+
+        assert_eq!(read_frame(&mut reader).await.unwrap(), Some(first));
+        assert_eq!(read_frame(&mut reader).await.unwrap(), Some(second));
+    }
+
+    #[tokio::test]
+    async fn read_frame_ends_on_close_between_messages() {
+        let (mut client, mut reader) = tcp_pair().await;
+        client.write_all(&chat("bye")).await.unwrap();
+        drop(client);
+        assert!(read_frame(&mut reader).await.unwrap().is_some());
+        assert_eq!(read_frame(&mut reader).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn read_frame_rejects_a_partial_header() {
+        let (mut client, mut reader) = tcp_pair().await;
+        client.write_all(&chat("cut")[..RORNET_HEADER_SIZE - 1]).await.unwrap();
+        drop(client);
+        let err: io::Error = read_frame(&mut reader).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn read_frame_rejects_a_partial_payload() {
+        let (mut client, mut reader) = tcp_pair().await;
+        let msg: Bytes = chat("cut short");
+        client.write_all(&msg[..msg.len() - 1]).await.unwrap();
+        drop(client);
+        let err: io::Error = read_frame(&mut reader).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn read_frame_rejects_an_oversized_message_without_waiting_for_it() {
+        let (mut client, mut reader) = tcp_pair().await;
+        let mut head: Vec<u8> = chat("")[..RORNET_HEADER_SIZE].to_vec();
+        head[12..16].copy_from_slice(&(RORNET_MAX_MESSAGE_LENGTH + 1).to_le_bytes());
+        client.write_all(&head).await.unwrap();
+
+        // The connection stays open, the header alone has to be enough
+        let frame = time::timeout(Duration::from_secs(1), read_frame(&mut reader)).await
+            .expect("read_frame waited for the payload");
+        assert_eq!(frame.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn tcp_handshakes_are_shared_out_per_address() {
+        setup();
+        let listener: Listener = Listener::new();
+        let conf: &Config = config::get();
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        // One /64 only gets its share, whatever address in it is used
+        let mut held: Vec<HandshakePermit> = (0..*conf.get_max_pending_per_ip())
+            .map(|i| listener.start_tcp_handshake(ip(&format!("2001:db8::{}", i + 1))).unwrap())
+            .collect();
+        assert!(listener.start_tcp_handshake(ip("2001:db8::ffff")).is_none());
+        held.pop();
+        assert!(listener.start_tcp_handshake(ip("2001:db8::ffff")).is_some());
+
+        // Other addresses fill up the rest, until there is none left at all
+        let mut others: Vec<HandshakePermit> = Vec::new();
+        let mut host: u32 = 1;
+        while let Some(permit) = listener.start_tcp_handshake(IpAddr::V4(Ipv4Addr::from(0xcb007100 + host))) {
+            others.push(permit);
+            host += 1;
+        }
+        assert_eq!(held.len() + others.len(), *conf.get_max_pending_clients() as usize);
+        assert!(listener.start_tcp_handshake(ip("198.51.100.1")).is_none());
+        drop(others);
+        assert_eq!(listener.tcp_handshakes_by_ip.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn tcp_client_ignores_datagrams_from_its_address() {
+        setup();
+        let mut listener: Listener = Listener::new();
+        listener.password_hash = None;
+        let listener: Arc<Listener> = Arc::new(listener);
+        let udp: Transport = Transport::Udp(Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()));
+
+        let tcp: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client: TcpStream = TcpStream::connect(tcp.local_addr().unwrap()).await.unwrap();
+        let (stream, src_addr) = tcp.accept().await.unwrap();
+        let handshake: HandshakePermit = listener.start_tcp_handshake(src_addr.ip()).unwrap();
+        let served: JoinHandle<()> = tokio::spawn(listener.clone().serve_tcp(stream, src_addr, handshake));
+
+        client.write_all(&Packet::Hello(net::RORNET_VERSION.to_string()).encode(0, 0).unwrap()).await.unwrap();
+        let mut user: UserInfo = UserInfo::default();
+        user.set_username("alice");
+        client.write_all(&Packet::UserInfo(MessageType::UserInfo, Box::new(user)).encode(0, 0).unwrap()).await.unwrap();
+        let (mut replies, writer) = client.into_split();
+        let mut commands: Vec<MessageType> = Vec::new();
+        while !commands.contains(&MessageType::Welcome) {
+            let frame: Bytes = read_frame(&mut replies).await.unwrap().unwrap();
+            commands.push(Packet::decode_from_server(&frame).unwrap().0.command);
+        }
+        assert!(matches!(listener.clients.lock().await[&src_addr].transport, Transport::Tcp(_)));
+
+        // A spoofed datagram with the client's address cannot make it leave
+        let leave: Bytes = Packet::Raw(MessageType::UserLeave, Bytes::new()).encode(1, 0).unwrap();
+        listener.process_client_data(&udp, src_addr, leave).await;
+        assert!(listener.clients.lock().await.contains_key(&src_addr));
+        assert!(!served.is_finished());
+
+        // Closing the connection is what ends the client
+        drop(writer);
+        served.await.unwrap();
+        assert!(!listener.clients.lock().await.contains_key(&src_addr));
+    }
+
+    #[test]
+    fn throttle_key_groups_ipv6_by_64() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(throttle_key(ip("2001:db8:1:2:aaaa::1")), ip("2001:db8:1:2::"));
+        assert_eq!(throttle_key(ip("2001:db8:1:2:ffff:ffff:ffff:ffff")), ip("2001:db8:1:2::"));
+        assert_ne!(throttle_key(ip("2001:db8:1:3::1")), throttle_key(ip("2001:db8:1:2::1")));
+        assert_eq!(throttle_key(ip("203.0.113.7")), ip("203.0.113.7"));
+        assert_eq!(throttle_key(ip("::ffff:203.0.113.7")), ip("203.0.113.7"));
+    }
+}
@@ -0,0 +1,163 @@
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+
+use crate::logger::{self, LogLevel};
+
+use super::canonical_ip;
+
+/// Addresses not allowed to join, read from the blacklist file. Every line
+/// holds one IPv4 or IPv6 address, optionally with a prefix length to ban a
+/// whole network, e.g. `198.51.100.0/24` or `2001:db8::/32`. Lines starting
+/// with `#` or `;` are comments.
+#[derive(Default)]
+pub struct Blacklist {
+    entries: Vec<(IpAddr, u8)>,
+}
+
+impl Blacklist {
+    /// Reads the blacklist file. A missing file bans nobody, lines that do
+    /// not parse are skipped with a warning.
+    pub fn load(path: &str) -> Blacklist {
+        match fs::read_to_string(path) {
+            Ok(text) => Blacklist::parse(&text, path),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Blacklist::default(),
+            Err(err) => {
+                logger::log(LogLevel::Warn, &format!("Could not read blacklist file {}: {}", path, err));
+                Blacklist::default()
+            }
+        }
+    }
+
+    fn parse(text: &str, path: &str) -> Blacklist {
+        let mut entries: Vec<(IpAddr, u8)> = Vec::new();
+        for (line_no, line) in text.lines().enumerate() {
+            let line: &str = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            match parse_entry(line) {
+                Some(entry) => entries.push(entry),
+                None => logger::log(LogLevel::Warn,
+                    &format!("{}:{}: not an address or network: {}", path, line_no + 1, line)),
+            }
+        }
+        Blacklist { entries }
+    }
+
+    pub fn len(&self) -> usize { self.entries.len() }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let ip: IpAddr = canonical_ip(ip);
+        self.entries.iter().any(|(net, prefix)| in_network(ip, *net, *prefix))
+    }
+}
+
+// `addr` or `addr/prefix`, a bare address bans only itself
+fn parse_entry(entry: &str) -> Option<(IpAddr, u8)> {
+    let (addr, prefix) = match entry.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (entry, None),
+    };
+    let addr: IpAddr = canonical_ip(addr.trim().parse().ok()?);
+    let max_prefix: u8 = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix: u8 = match prefix {
+        Some(prefix) => prefix.trim().parse().ok().filter(|prefix| *prefix <= max_prefix)?,
+        None => max_prefix,
+    };
+    Some((addr, prefix))
+}
+
+fn in_network(ip: IpAddr, net: IpAddr, prefix: u8) -> bool {
+    match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask: u32 = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        },
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask: u128 = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn blacklist(text: &str) -> Blacklist {
+        Blacklist::parse(text, "test.blacklist")
+    }
+
+    #[test]
+    fn prefix_zero_matches_the_whole_family() {
+        let v4 = blacklist("0.0.0.0/0");
+        assert!(v4.is_banned(ip("203.0.113.7")));
+        assert!(v4.is_banned(ip("255.255.255.255")));
+        assert!(!v4.is_banned(ip("2001:db8::1")));
+
+        let v6 = blacklist("::/0");
+        assert!(v6.is_banned(ip("2001:db8::1")));
+        assert!(!v6.is_banned(ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn full_prefix_matches_one_address() {
+        let bans = blacklist("203.0.113.7/32\n2001:db8::7/128");
+        assert!(bans.is_banned(ip("203.0.113.7")));
+        assert!(!bans.is_banned(ip("203.0.113.8")));
+        assert!(bans.is_banned(ip("2001:db8::7")));
+        assert!(!bans.is_banned(ip("2001:db8::8")));
+    }
+
+    #[test]
+    fn bare_address_is_a_full_prefix() {
+        assert_eq!(parse_entry("203.0.113.7"), Some((ip("203.0.113.7"), 32)));
+        assert_eq!(parse_entry("2001:db8::7"), Some((ip("2001:db8::7"), 128)));
+    }
+
+    #[test]
+    fn networks_match_inside_only() {
+        let bans = blacklist("198.51.100.0/24\n2001:db8:1::/48");
+        assert!(bans.is_banned(ip("198.51.100.255")));
+        assert!(!bans.is_banned(ip("198.51.101.0")));
+        assert!(bans.is_banned(ip("2001:db8:1:ffff::1")));
+        assert!(!bans.is_banned(ip("2001:db8:2::1")));
+    }
+
+    #[test]
+    fn v4_mapped_addresses_match_ipv4_entries() {
+        let bans = blacklist("198.51.100.0/24");
+        assert!(bans.is_banned(ip("::ffff:198.51.100.9")));
+        assert!(!bans.is_banned(ip("::ffff:198.51.101.9")));
+
+        // A v4-mapped entry bans the IPv4 address it stands for
+        let bans = blacklist("::ffff:203.0.113.7");
+        assert_eq!(bans.entries, vec![(ip("203.0.113.7"), 32)]);
+        assert!(bans.is_banned(ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn invalid_entries_are_skipped() {
+        assert_eq!(parse_entry("203.0.113.0/33"), None);
+        assert_eq!(parse_entry("2001:db8::/129"), None);
+        assert_eq!(parse_entry("203.0.113.0/-1"), None);
+        assert_eq!(parse_entry("203.0.113.0/"), None);
+        assert_eq!(parse_entry("203.0.113.0/x"), None);
+        assert_eq!(parse_entry("example.com"), None);
+
+        let bans = blacklist("203.0.113.0/33\nnot an address\n198.51.100.7");
+        assert_eq!(bans.len(), 1);
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_ignored() {
+        let bans = blacklist("# spammers\n; more spammers\n\n   \n  198.51.100.7  \n");
+        assert_eq!(bans.entries, vec![(ip("198.51.100.7"), 32)]);
+    }
+}
@@ -0,0 +1,64 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::config::{self, Config};
+
+/// Chat flood protection for one client. Sending more than the configured
+/// number of messages within the interval gags the client for a while.
+pub struct SpamFilter {
+    // When the messages still inside the interval were sent
+    sent: VecDeque<Instant>,
+    gagged_until: Option<Instant>,
+}
+
+impl SpamFilter {
+    pub fn new() -> SpamFilter {
+        SpamFilter {
+            sent: VecDeque::new(),
+            gagged_until: None,
+        }
+    }
+
+    /// Counts a chat message. False if it must be dropped, either because
+    /// the client is gagged or because this message got it gagged.
+    pub fn allow(&mut self, now: Instant) -> bool {
+        let conf: &Config = config::get();
+        if self.is_gagged(now) {
+            return false;
+        }
+        let max_count: i32 = *conf.get_spamfilter_msg_count();
+        if max_count <= 0 || *conf.get_spamfilter_msg_interval_sec() <= 0 {
+            return true;
+        }
+        self.sent.push_back(now);
+        if self.sent.len() > max_count as usize {
+            let gag = Duration::from_secs((*conf.get_spamfilter_gag_duration_sec()).max(0) as u64);
+            self.gagged_until = Some(now + gag);
+            self.sent.clear();
+            return false;
+        }
+        true
+    }
+
+    pub fn is_gagged(&self, now: Instant) -> bool {
+        self.gagged_until.is_some_and(|until| now < until)
+    }
+
+    /// Forgets messages that left the interval and lifts gags that ran out
+    pub fn decay(&mut self, now: Instant) {
+        let interval = Duration::from_secs((*config::get().get_spamfilter_msg_interval_sec()).max(0) as u64);
+        while self.sent.front().is_some_and(|sent| now - *sent > interval) {
+            self.sent.pop_front();
+        }
+        if !self.is_gagged(now) {
+            self.gagged_until = None;
+        }
+    }
+}
+
+impl Default for SpamFilter {
+    fn default() -> SpamFilter {
+        SpamFilter::new()
+    }
+}
@@ -0,0 +1,95 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Server wide counters. Traffic is counted as it happens, everything else
+/// is sampled once per tick.
+pub struct Stats {
+    started: Instant,
+    packets_in: AtomicU64,
+    bytes_in: AtomicU64,
+    packets_out: AtomicU64,
+    bytes_out: AtomicU64,
+    // Discardable messages not sent because a client's queue was full
+    dropped: AtomicU64,
+    clients: AtomicUsize,
+    peak_clients: AtomicUsize,
+    ticks: AtomicU64,
+    // Ticks that started after the next one was already due
+    late_ticks: AtomicU64,
+    max_tick_lag_us: AtomicU64,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            started: Instant::now(),
+            packets_in: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            packets_out: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            clients: AtomicUsize::new(0),
+            peak_clients: AtomicUsize::new(0),
+            ticks: AtomicU64::new(0),
+            late_ticks: AtomicU64::new(0),
+            max_tick_lag_us: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_in(&self, bytes: usize) {
+        self.packets_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_out(&self, bytes: usize) {
+        self.packets_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records how long after its deadline a tick started
+    pub fn record_tick(&self, lag: Duration, period: Duration) {
+        self.ticks.fetch_add(1, Ordering::Relaxed);
+        if lag >= period {
+            self.late_ticks.fetch_add(1, Ordering::Relaxed);
+        }
+        self.max_tick_lag_us.fetch_max(lag.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn sample_clients(&self, connected: usize) {
+        self.clients.store(connected, Ordering::Relaxed);
+        self.peak_clients.fetch_max(connected, Ordering::Relaxed);
+    }
+
+    pub fn late_ticks(&self) -> u64 { self.late_ticks.load(Ordering::Relaxed) }
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats::new()
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f,
+            "up {}s, {} clients (peak {}), in {} packets / {} bytes, out {} packets / {} bytes ({} dropped), \
+             {} ticks ({} late, max lag {}ms)",
+            self.started.elapsed().as_secs(),
+            self.clients.load(Ordering::Relaxed),
+            self.peak_clients.load(Ordering::Relaxed),
+            self.packets_in.load(Ordering::Relaxed),
+            self.bytes_in.load(Ordering::Relaxed),
+            self.packets_out.load(Ordering::Relaxed),
+            self.bytes_out.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed),
+            self.ticks.load(Ordering::Relaxed),
+            self.late_ticks(),
+            self.max_tick_lag_us.load(Ordering::Relaxed) / 1000)
+    }
+}
@@ -38,7 +38,7 @@ pub fn log(level: LogLevel, msg: &str) {
 
     // Implement thread logging (get the tid and stuff ykyk)
 
-    print!("{}|\t{}|{}\n", local_time, level_str, msg);
+    println!("{}|\t{}|{}", local_time, level_str, msg);
 
     // Grab mutex lock for file to print to file
 }
\ No newline at end of file
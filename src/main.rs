@@ -1,15 +1,11 @@
-mod config;
-mod logger;
-mod master_server;
-mod listener;
-mod net;
+use std::net::IpAddr;
+use std::sync::Arc;
 
-use tokio::sync::broadcast;
-
-use config::{Config, ServerType, CONF};
-use logger::LogLevel;
-use master_server::{Client, retrieve_public_ip};
-use listener::Listener;
+use rust_of_rods::{config, logger, master_server};
+use rust_of_rods::config::{Config, ServerType};
+use rust_of_rods::logger::LogLevel;
+use rust_of_rods::master_server::retrieve_public_ip;
+use rust_of_rods::listener::Listener;
 
 
 #[tokio::main]
@@ -18,7 +14,7 @@ async fn main() {
 
     let conf: &Config = match Config::build() {
         // We know if true then CONF is not None so it's safe
-        true => unsafe { &CONF.as_ref().unwrap() },
+        true => config::get(),
         false => {
             logger::log(LogLevel::Error, "Failed to parse server ini files, exiting...");
             return
@@ -40,17 +36,34 @@ async fn main() {
     if let ServerType::Inet | ServerType::Auto = server_mode {
         logger::log(LogLevel::Info, "Starting server in INET mode");
         // Sets IP address automatically if it's not already set in ini files
-        unsafe {
-            let ip_addr: &str = conf.get_ip_addr();
-            if ip_addr.is_empty() || ip_addr.eq("0.0.0.0") {
+        let configured_ip: Option<IpAddr> = match conf.get_ip_addr() {
+            "" => None,
+            ip_addr => match ip_addr.parse::<IpAddr>() {
+                Ok(ip) => Some(ip).filter(|ip| !ip.is_unspecified()),
+                Err(_) => {
+                    logger::log(LogLevel::Error,
+                        &format!("Configured IP {} is not a valid address, exiting...", ip_addr));
+                    return;
+                }
+            },
+        };
+        let ip_addr: IpAddr = match configured_ip {
+            Some(ip) => ip,
+            None => {
                 logger::log(LogLevel::Warn, "No IP given, detecting...");
-                if let Err(e) = retrieve_public_ip().await {
-                    logger::log(LogLevel::Error, &e.to_string());
-                    return
-                };
+                match retrieve_public_ip().await {
+                    Some(ip) => {
+                        config::set_detected_ip(ip);
+                        ip
+                    },
+                    None => {
+                        logger::log(LogLevel::Error, "Could not detect public IP, exiting...");
+                        return
+                    }
+                }
             }
-        }
-        logger::log(LogLevel::Info, &format!("IP Address is: {}", conf.get_ip_addr()));
+        };
+        logger::log(LogLevel::Info, &format!("IP Address is: {}", ip_addr));
 
         if conf.get_server_name().is_empty() {
             logger::log(LogLevel::Error, "Server name not specified, exiting...");
@@ -58,15 +71,28 @@ async fn main() {
         }
         logger::log(LogLevel::Info, &format!("Server name: {}", &conf.get_server_name()));
 
-        // master server registration should be in a seperate blocking thread
-        // let mut master: Client = Client::new();
-        // master.register();
+        let listener = Arc::new(Listener::new());
+
+        // Each serverlist is registered on and heartbeated in its own task
+        let serverlists = match master_server::start(
+            listener.subscribe_users(), listener.subscribe_heartbeats(), listener.subscribe_shutdown()) {
+            Ok(handles) => handles,
+            Err(err) => {
+                logger::log(LogLevel::Error,
+                    &format!("Could not set up serverlist client: {}, exiting...", err));
+                return;
+            }
+        };
 
         // start listener, blocking thread
-        let listener = Listener::new();
-        if let Err(err) = listener.run().await {
+        if let Err(err) = listener.clone().run().await {
             eprintln!("Error occurred: {}", err);
             // Code to handle the error
         }
+        // The serverlists unregister once the listener stopped
+        for serverlist in serverlists {
+            let _ = serverlist.await;
+        }
+        logger::log(LogLevel::Info, &format!("Final stats: {}", listener.stats()));
     }
 }
\ No newline at end of file
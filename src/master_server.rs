@@ -1,50 +1,137 @@
-use futures::executor::block_on;
-use reqwest::{Method, Response, Error};
+use std::fs;
+use std::net::{IpAddr, Ipv6Addr};
+use std::time::Duration;
+
+use reqwest::{Certificate, ClientBuilder, Method, Proxy, Response, Error};
 use serde_json::{Value, json};
+use tokio::sync::{broadcast, watch};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+use tokio::time;
 
-use crate::config::{Config, CONF};
+use crate::config::{self, Config, IpDetectMode};
 use crate::logger::{LogLevel, self};
+use crate::net::RORNET_VERSION;
 
 pub struct Client {
+    // Address registered for this server, IPv4 or IPv6
+    m_ip: String,
     m_token: Option<String>,
     m_trust_level: i32,
     m_is_registered: bool,
-    m_server_path: Option<String>
+    m_server_path: String,
+    m_http: reqwest::Client,
 }
 
 impl Client {
-    pub fn new() -> Client {
+    pub fn new(serverlist: &str, ip: &str, http: reqwest::Client) -> Client {
         Client {
+            m_ip: ip.to_string(),
             m_token: None,
             m_trust_level: -1,
             m_is_registered: false,
-            m_server_path: None,
+            m_server_path: format!("{}/server-list", serverlist),
+            m_http: http,
+        }
+    }
+
+    /// Keeps the server registered on this client's serverlist until the
+    /// server shuts down, then unregisters it.
+    pub async fn run(
+        mut self,
+        users: watch::Receiver<Value>,
+        heartbeats: broadcast::Receiver<()>,
+        mut shutdown: watch::Receiver<bool>
+    ) {
+        tokio::select! {
+            _ = self.keep_registered(&users, heartbeats) => return,
+            _ = shutdown.wait_for(|stopping| *stopping) => (),
+        }
+        if self.m_is_registered && self.unregister().await {
+            logger::log(LogLevel::Info, &format!("Unregistered from serverlist {}", self.m_server_path));
         }
     }
 
+    // Sends a heartbeat with the current user list whenever one is due.
+    // Registers again if the list stops accepting heartbeats and gives up if
+    // registration keeps failing.
+    async fn keep_registered(&mut self, users: &watch::Receiver<Value>, mut heartbeats: broadcast::Receiver<()>) {
+        let conf: &Config = config::get();
+        let retry_count: u32 = *conf.get_heartbeat_retry_count();
+        let retry_delay = Duration::from_secs(*conf.get_heartbeat_retry_seconds() as u64);
+
+        loop {
+            let mut attempts: u32 = 0;
+            while !self.register().await {
+                attempts += 1;
+                if attempts > retry_count {
+                    logger::log(LogLevel::Error,
+                        &format!("Giving up on serverlist {}", self.m_server_path));
+                    return;
+                }
+                time::sleep(retry_delay).await;
+            }
+            logger::log(LogLevel::Info,
+                &format!("Registered {} on serverlist {} with trust level {}",
+                    self.m_ip, self.m_server_path, self.m_trust_level));
+
+            // Heartbeat whenever the server tick says so, until the list stops
+            // answering, then register again
+            loop {
+                match heartbeats.recv().await {
+                    // Missed ticks still mean one heartbeat is due
+                    Ok(()) | Err(RecvError::Lagged(_)) => (),
+                    Err(RecvError::Closed) => return,
+                }
+                if !self.heartbeat_with_retry(users, retry_count, retry_delay).await {
+                    break;
+                }
+            }
+            logger::log(LogLevel::Warn,
+                &format!("Lost registration on serverlist {}, registering again", self.m_server_path));
+            self.m_is_registered = false;
+        }
+    }
+
+    async fn heartbeat_with_retry(
+        &self,
+        users: &watch::Receiver<Value>,
+        retry_count: u32,
+        retry_delay: Duration,
+    ) -> bool {
+        for attempt in 0..=retry_count {
+            if attempt > 0 {
+                time::sleep(retry_delay).await;
+            }
+            let user_list: Value = users.borrow().clone();
+            if self.send_heartbeat(user_list).await {
+                return true;
+            }
+        }
+        false
+    }
+
     /// Registers server on the server list
-    pub fn register(&mut self) -> bool {
-        let conf: &Config = unsafe { CONF.as_ref().unwrap() };
+    pub async fn register(&mut self) -> bool {
+        let conf: &Config = config::get();
 
         let port: String = conf.get_listen_port().to_string();
         let max_clients: String = conf.get_max_clients().to_string();
         let password: String = (conf.is_public() as u32).to_string();
         let data: Value = json!({
-            "ip": conf.get_ip_addr(),
+            "ip": &self.m_ip,
             "port": &port,
             "name": conf.get_server_name(),
             "terrain-name": conf.get_terrain_name(),
             "max-clients": &max_clients,
-            "version": "RoRnet_2.44",
+            "version": RORNET_VERSION,
             "use-password": &password
         });
 
-        self.m_server_path = Some(format!("{}/server-list", conf.get_serverlist_path()));
-        
         // Attempt to register onto the server list
         logger::log(LogLevel::Info, 
-            &format!("Attempting to register on serverlist {}", self.m_server_path.as_ref().unwrap()));
-        let response: Response = match block_on(self.http_request(Method::POST, data)) {
+            &format!("Attempting to register {} on serverlist {}", self.m_ip, self.m_server_path));
+        let response: Response = match self.http_request(Method::POST, data).await {
             Ok(res) => res,
             Err(err) => {
                 logger::log(LogLevel::Error, &err.to_string());
@@ -55,7 +142,7 @@ impl Client {
         // Try to parse a registration error
         let stat_code: u16 = response.status().as_u16();
         if stat_code != 200 {
-            let err_json: Value  = match block_on(response.json()) {
+            let err_json: Value  = match response.json().await {
                 Ok(res) => res,
                 Err(err) => {
                     logger::log(LogLevel::Error, "Could not properly parse server response. Exiting...");
@@ -64,12 +151,12 @@ impl Client {
                 }
             };
             logger::log(LogLevel::Error, 
-                &format!("HTTP {}: {}", stat_code, &err_json["message"]));
+                &format!("{} HTTP {}: {}", self.m_server_path, stat_code, &err_json["message"]));
             return false;
         }
 
         // Try to parse a successful registration into a serde_json::Value
-        match block_on(response.json::<Value>()) {
+        match response.json::<Value>().await {
             Ok(res) => {
                 let trust_level = &res["verified-level"].as_i64();
                 let challenge = &res["challenge"].as_str();
@@ -93,14 +180,14 @@ impl Client {
         }
     }
 
-    pub fn unregister(&mut self) -> bool {
-        assert_eq!(self.m_is_registered, true);
+    pub async fn unregister(&mut self) -> bool {
+        assert!(self.m_is_registered);
 
         let data: Value = json!({
             "challenge": self.m_token.as_ref().unwrap()
         });
 
-        let stat_code: u16 = match block_on(self.http_request(Method::DELETE, data)) {
+        let stat_code: u16 = match self.http_request(Method::DELETE, data).await {
             Ok(res) => res.status().as_u16(),
             Err(err) => {
                 logger::log(LogLevel::Error, &err.to_string());
@@ -109,7 +196,7 @@ impl Client {
         };
         if stat_code != 200 {
             logger::log(LogLevel::Error, 
-                &format!("Failed to unregister server with code HTTP {}", stat_code));
+                &format!("Failed to unregister server from {} with code HTTP {}", self.m_server_path, stat_code));
             return false;
         }
 
@@ -132,7 +219,7 @@ impl Client {
         };
         if stat_code != 200 {
             logger::log(LogLevel::Error, 
-                &format!("Heartbeat failed with code HTTP {}", stat_code));
+                &format!("Heartbeat to {} failed with code HTTP {}", self.m_server_path, stat_code));
             return false;
         }
         true
@@ -147,9 +234,8 @@ impl Client {
         method: Method, 
         payload: Value,
     ) -> Result<Response, Error> {
-        let client: reqwest::Client = reqwest::Client::new();
-        let res: Response = client
-            .request(method, self.m_server_path.as_ref().unwrap())
+        let res: Response = self.m_http
+            .request(method, &self.m_server_path)
             .json(&payload)
             .send().await?;
 
@@ -157,17 +243,293 @@ impl Client {
     }
 }
 
-/// Retrieves public ip of the computer hosting this server
-/// and mutates the global config accordingly to this new ip.
-pub async unsafe fn retrieve_public_ip() -> Result<(), Error> {
-    let conf: &mut Config = CONF.as_mut().unwrap() ;
-    let client: reqwest::Client = reqwest::Client::new();
-    let url: String = format!("{}/get-public-ip", conf.get_serverlist_path());
-    let ip: String = client
-        .get(url)
-        .send().await?
-        .text().await?;
-
-    conf.set_ip_addr(&ip);
-    Ok(())
-}
\ No newline at end of file
+/// Starts one registration task per configured serverlist, and a second one
+/// for the IPv6 address if there is one. Every list gets its own client, so a
+/// list that is slow or rejects us does not affect the others. The tasks
+/// unregister and end once `shutdown` turns true.
+pub fn start(
+    users: watch::Receiver<Value>,
+    heartbeats: broadcast::Receiver<()>,
+    shutdown: watch::Receiver<bool>
+) -> Result<Vec<JoinHandle<()>>, Box<dyn std::error::Error>> {
+    let conf: &Config = config::get();
+    let http: reqwest::Client = http_client_builder(conf)?.build()?;
+
+    let public_ip: IpAddr = conf.get_public_ip().ok_or("No public IP to register with")?;
+    let mut addresses: Vec<(IpAddr, reqwest::Client)> = vec![(public_ip, http)];
+    let ipv6_addr: &str = conf.get_ipv6_addr();
+    if !ipv6_addr.is_empty() {
+        let ipv6_addr: IpAddr = match ipv6_addr.parse::<Ipv6Addr>() {
+            Ok(ip) => IpAddr::V6(ip),
+            Err(_) => return Err(format!("{} is not an IPv6 address", ipv6_addr).into()),
+        };
+        if ipv6_addr != public_ip {
+            // Registering over IPv6 lets the list see the address it is asked to add
+            let http_v6: reqwest::Client = http_client_builder(conf)?
+                .local_address(IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+                .build()?;
+            addresses.push((ipv6_addr, http_v6));
+        }
+    }
+
+    Ok(conf.get_serverlist_paths().iter()
+        .flat_map(|path| addresses.iter().map(move |(ip, http)| (path, ip, http)))
+        .map(|(path, ip, http)| {
+            let client: Client = Client::new(path, &ip.to_string(), http.clone());
+            tokio::spawn(client.run(users.clone(), heartbeats.resubscribe(), shutdown.clone()))
+        })
+        .collect())
+}
+
+/// Sets up an HTTP client for talking to serverlists with the configured
+/// timeouts, proxy and extra root certificates.
+fn http_client_builder(conf: &Config) -> Result<ClientBuilder, Box<dyn std::error::Error>> {
+    let user_agent: String = format!("rust-of-rods/{} ({})", env!("CARGO_PKG_VERSION"), RORNET_VERSION);
+    let mut builder: ClientBuilder = reqwest::Client::builder()
+        .user_agent(user_agent)
+        .connect_timeout(Duration::from_secs(*conf.get_http_connect_timeout_sec() as u64))
+        .timeout(Duration::from_secs(*conf.get_http_timeout_sec() as u64));
+
+    if !conf.get_http_proxy().is_empty() {
+        builder = builder.proxy(Proxy::all(conf.get_http_proxy())?);
+    }
+    if !conf.get_http_ca_file().is_empty() {
+        for cert in load_ca_bundle(conf.get_http_ca_file())? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    Ok(builder)
+}
+
+// Reads every certificate out of a PEM bundle, for self-hosted serverlists
+// signed by a private CA
+fn load_ca_bundle(path: &str) -> Result<Vec<Certificate>, Box<dyn std::error::Error>> {
+    const PEM_END: &str = "-----END CERTIFICATE-----";
+
+    let pem: String = fs::read_to_string(path)?;
+    let certs: Vec<Certificate> = pem.split_inclusive(PEM_END)
+        .filter(|block| block.contains(PEM_END))
+        .map(|block| Certificate::from_pem(block.trim().as_bytes()))
+        .collect::<Result<_, _>>()?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", path).into());
+    }
+    Ok(certs)
+}
+
+/// Retrieves the public ip of the computer hosting this server. Depending on
+/// the configured detection mode this either asks the serverlist (falling back
+/// to any secondary endpoints) or looks for a public address on a local interface.
+pub async fn retrieve_public_ip() -> Option<IpAddr> {
+    let conf: &Config = config::get();
+
+    match conf.get_ip_detect_mode() {
+        IpDetectMode::Remote => retrieve_remote_ip(conf).await,
+        IpDetectMode::Local => retrieve_local_ip(conf.get_prefer_ipv6()),
+    }
+}
+
+async fn retrieve_remote_ip(conf: &Config) -> Option<IpAddr> {
+    // Binding the request to an IPv6 socket makes the endpoint see (and echo)
+    // our IPv6 address instead of the IPv4 one
+    let client = http_client_builder(conf).and_then(|mut builder| {
+        if conf.get_prefer_ipv6() {
+            builder = builder.local_address(IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+        }
+        Ok(builder.build()?)
+    });
+    let client: reqwest::Client = match client {
+        Ok(client) => client,
+        Err(err) => {
+            logger::log(LogLevel::Error, &err.to_string());
+            return None;
+        }
+    };
+
+    let mut urls: Vec<String> = conf.get_serverlist_paths().iter()
+        .map(|path| format!("{}/get-public-ip", path))
+        .collect();
+    urls.extend(conf.get_ip_detect_fallbacks().iter().cloned());
+    detect_ip(&client, &urls).await
+}
+
+// Asks each endpoint in turn until one reports a public address. A private
+// one (an endpoint on the LAN, or behind CGNAT) is only used if no endpoint
+// reports anything better.
+async fn detect_ip(client: &reqwest::Client, urls: &[String]) -> Option<IpAddr> {
+    let mut non_public: Option<IpAddr> = None;
+    for url in urls {
+        let text: String = match fetch_text(client, url).await {
+            Ok(text) => text,
+            Err(err) => {
+                logger::log(LogLevel::Warn, &format!("IP detection via {} failed: {}", url, err));
+                continue;
+            }
+        };
+        match text.trim().parse::<IpAddr>() {
+            Ok(ip) if is_public_ip(&ip) => return Some(ip),
+            Ok(ip) if !ip.is_unspecified() => {
+                logger::log(LogLevel::Warn,
+                    &format!("{} reported non-public address {}, trying the next endpoint", url, ip));
+                non_public = non_public.or(Some(ip));
+            },
+            _ => {
+                logger::log(LogLevel::Warn,
+                    &format!("IP detection via {} returned an invalid address", url));
+                logger::log(LogLevel::Debug, &format!("Raw response: {}", text));
+            },
+        }
+    }
+    if let Some(ip) = non_public {
+        logger::log(LogLevel::Warn,
+            &format!("No endpoint reported a public address, using {} which may be unreachable", ip));
+    }
+    non_public
+}
+
+async fn fetch_text(client: &reqwest::Client, url: &str) -> Result<String, Error> {
+    client.get(url).send().await?.error_for_status()?.text().await
+}
+
+/// Picks the first public address bound to a local interface, for hosts that
+/// are directly reachable without NAT.
+fn retrieve_local_ip(prefer_ipv6: bool) -> Option<IpAddr> {
+    let ifaces = match if_addrs::get_if_addrs() {
+        Ok(ifaces) => ifaces,
+        Err(err) => {
+            logger::log(LogLevel::Error, &format!("Could not list network interfaces: {}", err));
+            return None;
+        }
+    };
+
+    let (v4, v6): (Vec<IpAddr>, Vec<IpAddr>) = ifaces.iter()
+        .map(|iface| iface.ip())
+        .filter(is_public_ip)
+        .partition(IpAddr::is_ipv4);
+    let (first, second) = if prefer_ipv6 { (v6, v4) } else { (v4, v6) };
+    first.into_iter().chain(second).next()
+}
+
+/// Whether an address is globally routable, i.e. something clients on the
+/// internet could connect to.
+pub fn is_public_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            let shared = a == 100 && (b & 0xc0) == 64;      // 100.64.0.0/10 (CGNAT)
+            !(ip.is_unspecified() || ip.is_loopback() || ip.is_private()
+                || ip.is_link_local() || ip.is_broadcast()
+                || ip.is_documentation() || ip.is_multicast() || shared)
+        },
+        IpAddr::V6(ip) => {
+            let seg = ip.segments();
+            let unique_local = (seg[0] & 0xfe00) == 0xfc00;     // fc00::/7
+            let link_local = (seg[0] & 0xffc0) == 0xfe80;       // fe80::/10
+            let documentation = seg[0] == 0x2001 && seg[1] == 0xdb8;
+            !(ip.is_unspecified() || ip.is_loopback() || ip.is_multicast()
+                || unique_local || link_local || documentation
+                || ip.to_ipv4_mapped().is_some())
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_public(ip: &str) -> bool {
+        is_public_ip(&ip.parse().unwrap())
+    }
+
+    #[test]
+    fn global_addresses_are_public() {
+        assert!(is_public("8.8.8.8"));
+        assert!(is_public("2606:4700:4700::1111"));
+    }
+
+    #[test]
+    fn cgnat_is_not_public() {
+        assert!(!is_public("100.64.0.1"));
+        assert!(!is_public("100.127.255.255"));
+        // Just outside 100.64.0.0/10
+        assert!(is_public("100.63.255.255"));
+        assert!(is_public("100.128.0.1"));
+    }
+
+    #[test]
+    fn private_and_loopback_are_not_public() {
+        for ip in ["10.0.0.1", "172.16.0.1", "192.168.1.1", "127.0.0.1", "0.0.0.0", "::1", "::"] {
+            assert!(!is_public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn unique_local_ipv6_is_not_public() {
+        assert!(!is_public("fc00::1"));
+        assert!(!is_public("fd12:3456:789a::1"));
+        assert!(is_public("fe00::1"));
+    }
+
+    #[test]
+    fn link_local_is_not_public() {
+        assert!(!is_public("169.254.10.1"));
+        assert!(!is_public("fe80::1"));
+        assert!(!is_public("febf:ffff::1"));
+    }
+
+    #[test]
+    fn documentation_and_multicast_are_not_public() {
+        for ip in ["192.0.2.1", "198.51.100.1", "203.0.113.1", "2001:db8::1", "224.0.0.1", "ff02::1"] {
+            assert!(!is_public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn v4_mapped_ipv6_is_not_public() {
+        // Even with a public IPv4 address inside, it is not reachable as IPv6
+        assert!(!is_public("::ffff:8.8.8.8"));
+        assert!(!is_public("::ffff:10.0.0.1"));
+    }
+
+    // An HTTP endpoint on localhost that answers every request with `body`
+    async fn endpoint(body: &'static str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url: String = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0u8; 1024];
+                let _ = stream.read(&mut request).await;
+                let response: String = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}", body.len(), body);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        url
+    }
+
+    fn http() -> reqwest::Client {
+        reqwest::Client::builder().no_proxy().build().unwrap()
+    }
+
+    #[tokio::test]
+    async fn non_public_ip_falls_back_to_the_next_endpoint() {
+        let urls: Vec<String> = vec![
+            endpoint("192.168.1.20").await,
+            endpoint("not an address").await,
+            endpoint("100.64.3.4").await,
+            endpoint("8.8.8.8").await,
+        ];
+        assert_eq!(detect_ip(&http(), &urls).await, Some("8.8.8.8".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn non_public_ip_is_the_last_resort() {
+        let urls: Vec<String> = vec![endpoint("192.168.1.20").await, endpoint("10.0.0.1").await];
+        assert_eq!(detect_ip(&http(), &urls).await, Some("192.168.1.20".parse().unwrap()));
+
+        let urls: Vec<String> = vec![endpoint("0.0.0.0").await];
+        assert_eq!(detect_ip(&http(), &urls).await, None);
+    }
+}
@@ -0,0 +1,8 @@
+use std::time::Duration;
+
+/// Callbacks a server script receives. There is no script engine yet, so
+/// anything implementing this can be handed to `Listener::add_script`.
+pub trait Script: Send {
+    /// Called once per server tick with the time since the previous tick
+    fn frame_step(&mut self, dt: Duration);
+}
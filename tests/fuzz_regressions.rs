@@ -0,0 +1,33 @@
+// Replays every input saved under fuzz/regressions/<target>/ through the
+// matching fuzz target. Inputs that once crashed the server go there so they
+// keep being checked without cargo-fuzz.
+
+use std::fs;
+use std::path::Path;
+
+#[path = "../fuzz/harness.rs"]
+mod harness;
+
+fn replay(target: &str, run: fn(&[u8])) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/regressions").join(target);
+    for entry in fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        println!("replaying {}", path.display());
+        run(&fs::read(&path).unwrap());
+    }
+}
+
+#[test]
+fn decode_header() {
+    replay("decode_header", harness::decode_header);
+}
+
+#[test]
+fn decode_payload() {
+    replay("decode_payload", harness::decode_payload);
+}
+
+#[test]
+fn client_state_machine() {
+    replay("client_state_machine", harness::client_state_machine);
+}
@@ -0,0 +1,365 @@
+// Drives the listener's client state machine directly. Sockets on localhost
+// stand in for the clients, so whatever the server sends them can be read
+// back and checked.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Once};
+use std::time::Duration;
+
+use sha1::{Digest, Sha1};
+use tokio::net::UdpSocket;
+use tokio::time;
+
+use rust_of_rods::config::{self, Config};
+use rust_of_rods::listener::{Listener, Transport};
+use rornet::{
+    Bytes, Header, MessageType, Packet, StreamRegister, StreamType, UserInfo,
+    RORNET_VERSION, STREAM_REG_SUCCESS,
+};
+
+// Where the server password hash sits in a UserInfo payload
+const SERVERPASSWORD_OFFSET: usize = 96;
+
+fn setup() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        Config::build();
+    });
+}
+
+fn password_hash(password: &str) -> String {
+    Sha1::digest(password.as_bytes()).iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+// The server side of the test: a listener and the socket it answers on
+struct Server {
+    listener: Listener,
+    transport: Transport,
+}
+
+impl Server {
+    async fn new() -> Server {
+        setup();
+        Server {
+            listener: Listener::new(),
+            transport: Transport::Udp(Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap())),
+        }
+    }
+}
+
+struct Peer {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    uid: i32,
+}
+
+impl Peer {
+    async fn new() -> Peer {
+        Peer::bind("127.0.0.1").await
+    }
+
+    // A peer on another loopback address, which the server takes for
+    // another host
+    async fn bind(ip: &str) -> Peer {
+        let socket: UdpSocket = UdpSocket::bind((ip, 0)).await.unwrap();
+        let addr: SocketAddr = socket.local_addr().unwrap();
+        Peer { socket, addr, uid: -1 }
+    }
+
+    // Sends a Hello, then a UserInfo with `serverpassword` as it goes on
+    // the wire. The ServerInfo in between is read and dropped.
+    async fn handshake(&self, server: &Server, name: &str, serverpassword: &[u8]) {
+        self.send(server, &Packet::Hello(RORNET_VERSION.to_string()), 0, 0).await;
+        self.recv_all().await;
+        self.user_info(server, name, serverpassword).await;
+    }
+
+    // The second half of the handshake
+    async fn user_info(&self, server: &Server, name: &str, serverpassword: &[u8]) {
+        let mut user: UserInfo = UserInfo::default();
+        user.set_username(name);
+        let mut payload: Vec<u8> = user.encode();
+        payload[SERVERPASSWORD_OFFSET..SERVERPASSWORD_OFFSET + serverpassword.len()].copy_from_slice(serverpassword);
+        self.send(server, &Packet::Raw(MessageType::UserInfo, Bytes::from(payload)), 0, 0).await;
+    }
+
+    // Sends just a Hello, and what came back
+    async fn hello(&self, server: &Server) -> Vec<MessageType> {
+        self.send(server, &Packet::Hello(RORNET_VERSION.to_string()), 0, 0).await;
+        self.recv_commands().await
+    }
+
+    async fn join_with(server: &Server, name: &str, password: &str) -> Peer {
+        let mut peer: Peer = Peer::new().await;
+        peer.handshake(server, name, password_hash(password).as_bytes()).await;
+        if let Some((head, _)) = peer.recv_all().await.into_iter()
+            .find(|(head, _)| head.command == MessageType::Welcome) {
+            peer.uid = head.source;
+        }
+        peer
+    }
+
+    async fn join(server: &Server, name: &str) -> Peer {
+        let peer: Peer = Peer::join_with(server, name, config::get().get_public_pw()).await;
+        assert!(peer.uid > 0, "{} did not get a Welcome", name);
+        peer
+    }
+
+    async fn send(&self, server: &Server, packet: &Packet, source: i32, streamid: u32) {
+        let data: Bytes = packet.encode(source, streamid).unwrap();
+        server.listener.process_client_data(&server.transport, self.addr, data).await;
+    }
+
+    // Everything the server sent so far. Gives the client's writer task a
+    // moment to get its queue out first.
+    async fn recv_all(&self) -> Vec<(Header, Packet)> {
+        time::sleep(Duration::from_millis(50)).await;
+        let mut buf: Vec<u8> = vec![0; 65536];
+        let mut received: Vec<(Header, Packet)> = Vec::new();
+        while let Ok((len, _)) = self.socket.try_recv_from(&mut buf) {
+            received.push(Packet::decode_from_server(&Bytes::copy_from_slice(&buf[..len])).unwrap());
+        }
+        received
+    }
+
+    async fn recv_commands(&self) -> Vec<MessageType> {
+        self.recv_all().await.into_iter().map(|(head, _)| head.command).collect()
+    }
+}
+
+fn character_stream() -> Packet {
+    Packet::StreamRegister(MessageType::StreamRegister,
+        Box::new(StreamRegister::new(StreamType::Character, 0, 0, "default")))
+}
+
+fn stream_data() -> Packet {
+    Packet::Raw(MessageType::StreamData, Bytes::from_static(b"position"))
+}
+
+#[tokio::test]
+async fn user_leave_removes_client() {
+    let server: Server = Server::new().await;
+    let users = server.listener.subscribe_users();
+    let alice: Peer = Peer::join(&server, "alice").await;
+    let bob: Peer = Peer::join(&server, "bob").await;
+    alice.recv_all().await;
+    assert_eq!(users.borrow().as_array().unwrap().len(), 2);
+
+    alice.send(&server, &Packet::Raw(MessageType::UserLeave, Bytes::new()), alice.uid, 0).await;
+
+    let received: Vec<(Header, Packet)> = bob.recv_all().await;
+    assert!(received.iter().any(|(head, _)|
+        head.command == MessageType::UserLeave && head.source == alice.uid));
+    assert_eq!(users.borrow().as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn data_on_unregistered_stream_is_not_relayed() {
+    let server: Server = Server::new().await;
+    let alice: Peer = Peer::join(&server, "alice").await;
+    let bob: Peer = Peer::join(&server, "bob").await;
+    alice.recv_all().await;
+
+    alice.send(&server, &stream_data(), alice.uid, 10).await;
+    assert!(bob.recv_commands().await.is_empty());
+
+    // Nor on a stream someone else registered
+    bob.send(&server, &character_stream(), bob.uid, 10).await;
+    alice.recv_all().await;
+    alice.send(&server, &stream_data(), bob.uid, 10).await;
+    assert!(bob.recv_commands().await.is_empty());
+}
+
+#[tokio::test]
+async fn relayed_data_carries_sender_uid() {
+    let server: Server = Server::new().await;
+    let alice: Peer = Peer::join(&server, "alice").await;
+    let bob: Peer = Peer::join(&server, "bob").await;
+    alice.recv_all().await;
+
+    // Whatever source alice claims, the others see her own id
+    alice.send(&server, &character_stream(), bob.uid, 10).await;
+    alice.send(&server, &stream_data(), bob.uid, 10).await;
+
+    let received: Vec<(Header, Packet)> = bob.recv_all().await;
+    let register = received.iter().find(|(head, _)| head.command == MessageType::StreamRegister);
+    match register {
+        Some((head, Packet::StreamRegister(_, reg))) => {
+            assert_eq!(head.source, alice.uid);
+            assert_eq!((reg.origin_sourceid(), reg.origin_streamid()), (alice.uid, 10));
+        },
+        _ => panic!("bob did not get the stream registration"),
+    }
+    let data = received.iter().find(|(head, _)| head.command == MessageType::StreamData);
+    match data {
+        Some((head, Packet::Raw(_, payload))) => {
+            assert_eq!((head.source, head.streamid), (alice.uid, 10));
+            assert_eq!(&payload[..], b"position");
+        },
+        _ => panic!("bob did not get the stream data"),
+    }
+}
+
+#[tokio::test]
+async fn stream_register_result_goes_to_owner_only() {
+    let server: Server = Server::new().await;
+    let alice: Peer = Peer::join(&server, "alice").await;
+    let bob: Peer = Peer::join(&server, "bob").await;
+    let carol: Peer = Peer::join(&server, "carol").await;
+    alice.send(&server, &character_stream(), alice.uid, 10).await;
+    alice.recv_all().await;
+    bob.recv_all().await;
+    carol.recv_all().await;
+
+    let result = |streamid: i32| {
+        let mut reg: StreamRegister = StreamRegister::new(StreamType::Character, alice.uid, streamid, "default");
+        reg.set_status(STREAM_REG_SUCCESS);
+        Packet::StreamRegister(MessageType::StreamRegisterResult, Box::new(reg))
+    };
+    bob.send(&server, &result(10), bob.uid, 10).await;
+    assert_eq!(alice.recv_commands().await, vec![MessageType::StreamRegisterResult]);
+    assert!(carol.recv_commands().await.is_empty());
+    assert!(bob.recv_commands().await.is_empty());
+
+    // Alice has no stream 11, so there is nobody to tell
+    bob.send(&server, &result(11), bob.uid, 11).await;
+    assert!(alice.recv_commands().await.is_empty());
+    assert!(carol.recv_commands().await.is_empty());
+}
+
+#[tokio::test]
+async fn wrong_password_gets_wrong_pw() {
+    let server: Server = Server::new().await;
+    let peer: Peer = Peer::new().await;
+    peer.handshake(&server, "mallory", password_hash("guess").as_bytes()).await;
+    assert_eq!(peer.recv_commands().await, vec![MessageType::WrongPw]);
+}
+
+#[tokio::test]
+async fn uppercase_sha1_password_is_accepted() {
+    let server: Server = Server::new().await;
+    let password: &str = config::get().get_public_pw();
+    assert!(!password.is_empty());
+
+    let peer: Peer = Peer::join_with(&server, "alice", password).await;
+    assert!(peer.uid > 0);
+
+    // Neither the plaintext nor lowercase hex is what clients send
+    for serverpassword in [password.to_string(), password_hash(password).to_lowercase()] {
+        let peer: Peer = Peer::new().await;
+        peer.handshake(&server, "bob", serverpassword.as_bytes()).await;
+        assert_eq!(peer.recv_commands().await, vec![MessageType::WrongPw]);
+    }
+}
+
+#[tokio::test]
+async fn hellos_are_ignored_after_too_many_wrong_passwords() {
+    let server: Server = Server::new().await;
+    for _ in 0..3 {
+        let peer: Peer = Peer::new().await;
+        peer.handshake(&server, "mallory", password_hash("guess").as_bytes()).await;
+        assert_eq!(peer.recv_commands().await, vec![MessageType::WrongPw]);
+    }
+
+    let hello = Packet::Hello(RORNET_VERSION.to_string());
+    let peer: Peer = Peer::new().await;
+    peer.send(&server, &hello, 0, 0).await;
+    assert!(peer.recv_commands().await.is_empty());
+
+    // The throttle lasts a minute. The clock only stands still for the jump,
+    // sockets and writer tasks need real time.
+    time::pause();
+    time::advance(Duration::from_secs(61)).await;
+    time::resume();
+    peer.send(&server, &hello, 0, 0).await;
+    assert_eq!(peer.recv_commands().await, vec![MessageType::Hello]);
+}
+
+#[tokio::test]
+async fn hellos_get_full_once_max_clients_joined() {
+    let server: Server = Server::new().await;
+    let mut players: Vec<Peer> = Vec::new();
+    for i in 0..*config::get().get_max_clients() {
+        players.push(Peer::join(&server, &format!("player{}", i)).await);
+    }
+
+    let late: Peer = Peer::new().await;
+    assert_eq!(late.hello(&server).await, vec![MessageType::Full]);
+
+    // Someone leaving makes room again
+    let leaving: &Peer = &players[0];
+    leaving.send(&server, &Packet::Raw(MessageType::UserLeave, Bytes::new()), leaving.uid, 0).await;
+    assert_eq!(late.hello(&server).await, vec![MessageType::Hello]);
+}
+
+#[tokio::test]
+async fn pending_pool_is_shared_out_per_address() {
+    let server: Server = Server::new().await;
+    let conf: &Config = config::get();
+
+    // Handshakes that never finish, all from one host, only get its share
+    for _ in 0..*conf.get_max_pending_per_ip() {
+        assert_eq!(Peer::new().await.hello(&server).await, vec![MessageType::Hello]);
+    }
+    assert_eq!(Peer::new().await.hello(&server).await, vec![MessageType::Full]);
+
+    // Other hosts get the rest of the pool, and then nobody does
+    let free: u32 = conf.get_max_pending_clients() - conf.get_max_pending_per_ip();
+    for host in 0..free {
+        let peer: Peer = Peer::bind(&format!("127.0.0.{}", host + 2)).await;
+        assert_eq!(peer.hello(&server).await, vec![MessageType::Hello]);
+    }
+    let peer: Peer = Peer::bind(&format!("127.0.0.{}", free + 2)).await;
+    assert_eq!(peer.hello(&server).await, vec![MessageType::Full]);
+}
+
+// Lets `secs` pass for the server's timeouts, then runs a tick to sweep them
+async fn pass_time(server: &Server, secs: u64) {
+    time::pause();
+    time::advance(Duration::from_secs(secs)).await;
+    time::resume();
+    server.listener.process_tick(Duration::from_secs(secs)).await;
+}
+
+#[tokio::test]
+async fn pending_client_is_dropped_after_the_handshake_timeout() {
+    let server: Server = Server::new().await;
+    let timeout: u64 = *config::get().get_handshake_timeout_sec() as u64;
+    let stuck: Peer = Peer::new().await;
+    let slow: Peer = Peer::new().await;
+    assert_eq!(stuck.hello(&server).await, vec![MessageType::Hello]);
+    assert_eq!(slow.hello(&server).await, vec![MessageType::Hello]);
+
+    // Just in time still joins
+    pass_time(&server, timeout - 1).await;
+    let hash: String = password_hash(config::get().get_public_pw());
+    slow.user_info(&server, "slow", hash.as_bytes()).await;
+    assert!(slow.recv_commands().await.contains(&MessageType::Welcome));
+
+    // Too late is forgotten, a Hello starts over instead of ending the handshake
+    pass_time(&server, 2).await;
+    assert_eq!(stuck.hello(&server).await, vec![MessageType::Hello]);
+}
+
+#[tokio::test]
+async fn idle_client_is_dropped_and_peers_see_it_leave() {
+    let server: Server = Server::new().await;
+    let users = server.listener.subscribe_users();
+    let timeout: u64 = *config::get().get_idle_timeout_sec() as u64;
+    let idle: Peer = Peer::join(&server, "idle").await;
+    let active: Peer = Peer::join(&server, "active").await;
+    idle.recv_all().await;
+
+    pass_time(&server, timeout / 2).await;
+    let chat = Packet::Raw(MessageType::Utf8Chat, Bytes::from_static(b"still here"));
+    active.send(&server, &chat, active.uid, 0).await;
+    assert!(idle.recv_commands().await.contains(&MessageType::Utf8Chat));
+    assert_eq!(users.borrow().as_array().unwrap().len(), 2);
+
+    pass_time(&server, timeout - timeout / 2 + 1).await;
+    let received: Vec<(Header, Packet)> = active.recv_all().await;
+    assert!(received.iter().any(|(head, _)|
+        head.command == MessageType::UserLeave && head.source == idle.uid));
+    assert_eq!(users.borrow().as_array().unwrap().len(), 1);
+    assert!(idle.recv_commands().await.is_empty());
+}